@@ -1,10 +1,18 @@
 global start
+global stack_top
+global p4_table
+global gdt64_pointer
 extern long_mode_start
 
-section .text
+; virtual address where the kernel is linked, see `linker.ld`
+KERNEL_OFFSET equ 0xffffffff80000000
+
+; The boot code runs before paging is enabled, so all the symbols outside of the
+; `.boot` section must be accessed through their physical address.
+section .boot exec
 bits 32
 start:
-    mov esp, stack_top
+    mov esp, stack_top - KERNEL_OFFSET
     ; Move Multiboot info pointer to edi to pass it to the kernel. We must not
     ; modify the `edi` register until the kernel it called.
     mov edi, ebx
@@ -18,25 +26,32 @@ start:
     call set_up_SSE
 
     ; load the 64-bit GDT
-    lgdt [gdt64.pointer]
+    lgdt [gdt64.physical_pointer - KERNEL_OFFSET]
 
     jmp gdt64.code:long_mode_start
 
 set_up_page_tables:
-    ; recursive map P4
-    mov eax, p4_table
+    ; recursive map P4 (the last entry is used by the kernel)
+    mov eax, p4_table - KERNEL_OFFSET
     or eax, 0b11 ; present + writable
-    mov [p4_table + 511 * 8], eax
+    mov [p4_table - KERNEL_OFFSET + 510 * 8], eax
 
-    ; map first P4 entry to P3 table
-    mov eax, p3_table
+    ; map first P4 entry to P3 table, to identity map the boot code
+    mov eax, p3_table - KERNEL_OFFSET
     or eax, 0b11 ; present + writable
-    mov [p4_table], eax
+    mov [p4_table - KERNEL_OFFSET], eax
+
+    ; map the last P4 entry to the same P3 table, for the higher half kernel
+    mov [p4_table - KERNEL_OFFSET + 511 * 8], eax
 
     ; map first P3 entry to P2 table
-    mov eax, p2_table
+    mov eax, p2_table - KERNEL_OFFSET
     or eax, 0b11 ; present + writable
-    mov [p3_table], eax
+    mov [p3_table - KERNEL_OFFSET], eax
+
+    ; map the P3 entry of `KERNEL_OFFSET` to the same P2 table, so the first GiB
+    ; is also mapped at `KERNEL_OFFSET`
+    mov [p3_table - KERNEL_OFFSET + 510 * 8], eax
 
     ; map each P2 entry to a huge 2MiB page
     mov ecx, 0 ; counter variable
@@ -45,7 +60,7 @@ set_up_page_tables:
     mov eax, 0x200000  ; 2MiB
     mul ecx            ; start address of ecx-th page
     or eax, 0b10000011 ; present + writable + huge
-    mov [p2_table + ecx * 8], eax ; map ecx-th entry
+    mov [p2_table - KERNEL_OFFSET + ecx * 8], eax ; map ecx-th entry
 
     inc ecx            ; increase counter
     cmp ecx, 512       ; if counter == 512, the whole P2 table is mapped
@@ -55,7 +70,7 @@ set_up_page_tables:
 
 enable_paging:
     ; load P4 to cr3 register (cpu uses this to access the P4 table)
-    mov eax, p4_table
+    mov eax, p4_table - KERNEL_OFFSET
     mov cr3, eax
 
     ; enable PAE-flag in cr4 (Physical Address Extension)
@@ -188,6 +203,12 @@ gdt64:
     dq 0 ; zero entry
 .code: equ $ - gdt64 ; new
     dq (1<<44) | (1<<47) | (1<<43) | (1<<53) ; code segment
-.pointer:
-    dw $ - gdt64 - 1
+.end:
+; used to load the GDT before paging is enabled
+.physical_pointer:
+    dw .end - gdt64 - 1
+    dq gdt64 - KERNEL_OFFSET
+; used to reload the GDT once we are running on the higher half
+gdt64_pointer:
+    dw gdt64.end - gdt64 - 1
     dq gdt64
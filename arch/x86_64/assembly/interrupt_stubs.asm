@@ -0,0 +1,94 @@
+; Entry points of all the interrupt vectors. Each stub pushes a dummy error code
+; when the CPU doesn't push one, then its vector, and jumps to the common entry.
+; The common entry saves all the general purpose registers, which together form
+; a `TrapFrame` (see `interrupts/trap_frame.rs`), and calls `interrupt_dispatch`
+; with it. The registers are restored from the frame, so the handlers can change
+; where the interrupted code resumes.
+; The CPU doesn't clear EFLAGS.AC on interrupts, so the common entry runs `clac`
+; when SMAP is enabled, otherwise an interrupt taken inside a user access window
+; would run its handler with the user pages accessible. `iretq` restores the AC
+; flag of the interrupted code.
+
+global interrupt_stubs
+extern interrupt_dispatch
+extern SMAP_ENABLED
+
+%macro INTERRUPT_STUB 1
+interrupt_stub_%1:
+; the CPU pushes an error code for #DF, #TS, #NP, #SS, #GP, #PF, #AC, #CP, #VC
+; and #SX
+%if !(%1 == 8 || (%1 >= 10 && %1 <= 14) || %1 == 17 || %1 == 21 || %1 == 29 || %1 == 30)
+    push qword 0
+%endif
+    push qword %1
+    jmp interrupt_common
+%endmacro
+
+%macro INTERRUPT_STUB_ADDRESS 1
+    dq interrupt_stub_%1
+%endmacro
+
+section .text
+bits 64
+%assign vector 0
+%rep 256
+    INTERRUPT_STUB vector
+%assign vector vector + 1
+%endrep
+
+interrupt_common:
+    push rax
+    push rbx
+    push rcx
+    push rdx
+    push rsi
+    push rdi
+    push rbp
+    push r8
+    push r9
+    push r10
+    push r11
+    push r12
+    push r13
+    push r14
+    push r15
+
+    ; the stack is 16 byte aligned here, the CPU aligns it before pushing the
+    ; interrupt stack frame
+    cld
+    cmp byte [rel SMAP_ENABLED], 0
+    je .smap_disabled
+    clac
+.smap_disabled:
+    mov rdi, rsp
+    call interrupt_dispatch
+
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop r11
+    pop r10
+    pop r9
+    pop r8
+    pop rbp
+    pop rdi
+    pop rsi
+    pop rdx
+    pop rcx
+    pop rbx
+    pop rax
+
+    ; drop the vector and the error code
+    add rsp, 16
+    iretq
+
+; address of the stub of each vector
+section .rodata
+align 8
+interrupt_stubs:
+%assign vector 0
+%rep 256
+    INTERRUPT_STUB_ADDRESS vector
+%assign vector vector + 1
+%endrep
@@ -9,10 +9,27 @@
 
 global long_mode_start
 extern rust_main
+extern stack_top
+extern gdt64_pointer
 
-section .text
+; virtual address where the kernel is linked, see `linker.ld`
+KERNEL_OFFSET equ 0xffffffff80000000
+
+section .boot exec
 bits 64
 long_mode_start:
+    ; we are still running on the identity mapped boot code, jump to the higher half
+    mov rax, higher_half_start
+    jmp rax
+
+section .text
+bits 64
+higher_half_start:
+    ; move the stack and the GDT to their higher half addresses, the identity
+    ; mapping is removed when the kernel is remapped
+    mov rsp, stack_top
+    lgdt [gdt64_pointer]
+
     ; load 0 into all data segment registers
     mov ax, 0
     mov ss, ax
@@ -21,14 +38,18 @@ long_mode_start:
     mov fs, ax
     mov gs, ax
 
+    ; end the frame pointer chain walked by the backtraces
+    xor rbp, rbp
+
     ; call rust main (with multiboot pointer in rdi)
     call rust_main
 .os_returned:
     ; rust main returned, print `OS returned!`
+    mov rbx, KERNEL_OFFSET + 0xb8000
     mov rax, 0x4f724f204f534f4f
-    mov [0xb8000], rax
+    mov [rbx], rax
     mov rax, 0x4f724f754f744f65
-    mov [0xb8008], rax
+    mov [rbx + 8], rax
     mov rax, 0x4f214f644f654f6e
-    mov [0xb8010], rax
+    mov [rbx + 16], rax
     hlt
@@ -0,0 +1,73 @@
+//! # Backtraces
+//!
+//! The kernel is built with frame pointers, so each function saves the frame pointer of its caller
+//! right below its return address. The backtraces follow that chain until the null frame pointer
+//! set by the boot code, and resolve each return address with the kernel symbols.
+
+pub use self::symbols::{Demangled, SymbolSections, find_sections, init, resolve};
+
+use memory::paging::{Mapper, VirtualAddress, USER_END};
+
+mod symbols;
+
+/// Maximum number of frames printed, it stops the walk on corrupted chains that loop.
+const MAX_FRAMES: usize = 32;
+
+/// Print the call chain that leads to the caller.
+#[inline(never)]
+pub fn print() {
+    let frame_pointer: usize;
+    unsafe { asm!("mov %rbp, $0" : "=r"(frame_pointer)) };
+
+    println!("Backtrace:");
+    print_frames(0, frame_pointer);
+}
+
+/// Print the call chain of interrupted code.
+///
+/// ## Params
+/// * `instruction_pointer` - address of the interrupted instruction.
+/// * `frame_pointer` - value of RBP when the code was interrupted.
+pub fn print_from(instruction_pointer: VirtualAddress, frame_pointer: usize) {
+    println!("Backtrace:");
+    print_address(0, instruction_pointer);
+    print_frames(1, frame_pointer);
+}
+
+/// Walk the frames from `frame_pointer`, numbering them from `index`.
+fn print_frames(mut index: usize, mut frame_pointer: usize) {
+    let mapper = unsafe { Mapper::new() };
+
+    while frame_pointer != 0 && index < MAX_FRAMES {
+        // the frames of the kernel are aligned and mapped, anything else is a broken chain
+        if frame_pointer % 8 != 0 || frame_pointer < USER_END ||
+           mapper.translate(frame_pointer).is_none() ||
+           mapper.translate(frame_pointer + 8).is_none() {
+            println!("{:>4}: invalid frame pointer {:#x}", index, frame_pointer);
+            return;
+        }
+
+        let (caller_frame_pointer, return_address) = unsafe {
+            (*(frame_pointer as *const usize), *((frame_pointer + 8) as *const usize))
+        };
+        if return_address == 0 {
+            return;
+        }
+
+        // the return address is after the call, step back so it resolves to the call itself
+        print_address(index, return_address - 1);
+
+        frame_pointer = caller_frame_pointer;
+        index += 1;
+    }
+}
+
+/// Print a frame of a backtrace.
+fn print_address(index: usize, address: VirtualAddress) {
+    match resolve(address) {
+        Some((name, offset)) => {
+            println!("{:>4}: {:>016x} {}+{:#x}", index, address, Demangled(name), offset)
+        }
+        None => println!("{:>4}: {:>016x} <unknown>", index, address),
+    }
+}
@@ -0,0 +1,267 @@
+//! # Kernel Symbols
+//!
+//! GRUB also loads the sections of the kernel that aren't allocated, the symbol table among them,
+//! and reports them on the ELF sections tag with their physical address. The symbol table and its
+//! string table are kept out of the frame allocator by `memory::reserved` and read through the
+//! physical memory direct map, so resolving an address needs neither the heap nor any lock.
+
+use core::{fmt, mem, slice, str};
+use multiboot2::BootInformation;
+use spin::Once;
+
+use memory::phys_to_virt;
+use memory::paging::{PhysicalAddress, VirtualAddress};
+
+/// Section type of the symbol table
+const SHT_SYMTAB: u32 = 2;
+/// Symbol type of the functions
+const STT_FUNC: u8 = 2;
+
+/// ELF section header, as listed on the ELF sections tag.
+#[allow(dead_code)]
+#[repr(C)]
+struct SectionHeader {
+    name: u32,
+    section_type: u32,
+    flags: u64,
+    address: u64,
+    offset: u64,
+    size: u64,
+    /// Index of the string table of a symbol table
+    link: u32,
+    info: u32,
+    address_align: u64,
+    entry_size: u64,
+}
+
+/// ELF symbol table entry.
+#[allow(dead_code)]
+#[repr(C)]
+struct Symbol {
+    /// Offset of the name on the string table
+    name: u32,
+    info: u8,
+    other: u8,
+    section_index: u16,
+    value: u64,
+    size: u64,
+}
+
+/// Physical location of the symbol table and of its string table.
+#[derive(Debug, Clone, Copy)]
+pub struct SymbolSections {
+    pub symbols: PhysicalAddress,
+    pub symbols_size: usize,
+    pub strings: PhysicalAddress,
+    pub strings_size: usize,
+}
+
+struct SymbolTable {
+    symbols: &'static [Symbol],
+    strings: &'static [u8],
+}
+
+impl SymbolTable {
+    /// Get the name of a symbol from the string table.
+    fn name(&self, symbol: &Symbol) -> Option<&'static str> {
+        let start = symbol.name as usize;
+        if start >= self.strings.len() {
+            return None;
+        }
+
+        let strings = &self.strings[start..];
+        let length = strings.iter().position(|&byte| byte == 0).unwrap_or(strings.len());
+        str::from_utf8(&strings[..length]).ok()
+    }
+}
+
+static SYMBOL_TABLE: Once<SymbolTable> = Once::new();
+
+/// Find the symbol table of the kernel and its string table on the ELF sections tag.
+///
+/// ## Returns
+/// `None` when the kernel was stripped or the bootloader didn't load the symbol table.
+pub fn find_sections(boot_info: &BootInformation) -> Option<SymbolSections> {
+    let headers = section_headers(boot_info);
+
+    let symbols = match headers.iter().find(|header| header.section_type == SHT_SYMTAB) {
+        Some(symbols) => symbols,
+        None => return None,
+    };
+    let strings = match headers.get(symbols.link as usize) {
+        Some(strings) => strings,
+        None => return None,
+    };
+
+    // the sections that aren't loaded keep a zero address
+    if symbols.address == 0 || strings.address == 0 {
+        return None;
+    }
+
+    Some(SymbolSections {
+        symbols: symbols.address as usize,
+        symbols_size: symbols.size as usize,
+        strings: strings.address as usize,
+        strings_size: strings.size as usize,
+    })
+}
+
+/// Load the kernel symbols used to resolve the backtraces, it must be called after `memory::init`
+/// since the symbols are read through the physical memory direct map.
+pub fn init(boot_info: &BootInformation) {
+    let sections = match find_sections(boot_info) {
+        Some(sections) => sections,
+        None => {
+            println!("backtrace: no kernel symbol table, the addresses won't be resolved");
+            return;
+        }
+    };
+
+    let table = SYMBOL_TABLE.call_once(|| unsafe {
+        SymbolTable {
+            symbols: slice::from_raw_parts(phys_to_virt(sections.symbols) as *const Symbol,
+                                           sections.symbols_size / mem::size_of::<Symbol>()),
+            strings: slice::from_raw_parts(phys_to_virt(sections.strings) as *const u8,
+                                           sections.strings_size),
+        }
+    });
+
+    debugln!("backtrace: {} kernel symbols", table.symbols.len());
+}
+
+/// Find the function that contains an address.
+///
+/// ## Returns
+/// The mangled name of the function and the offset of the address from its start, or `None` when
+/// the address isn't part of any function.
+pub fn resolve(address: VirtualAddress) -> Option<(&'static str, usize)> {
+    let table = match SYMBOL_TABLE.try() {
+        Some(table) => table,
+        None => return None,
+    };
+
+    table.symbols
+        .iter()
+        .filter(|symbol| symbol.info & 0xf == STT_FUNC)
+        .find(|symbol| {
+            let start = symbol.value as usize;
+            address >= start && address < start + symbol.size as usize
+        })
+        .and_then(|symbol| table.name(symbol).map(|name| (name, address - symbol.value as usize)))
+}
+
+/// Section headers of the ELF sections tag, the `ElfSection`s of `multiboot2` don't expose the
+/// type and the link of the sections.
+fn section_headers(boot_info: &BootInformation) -> &[SectionHeader] {
+    let tag = match boot_info.elf_sections_tag() {
+        Some(tag) => tag as *const _ as usize,
+        None => return &[],
+    };
+
+    // the tag starts with its type, size, number of sections, entry size and the index of the
+    // section names
+    let (count, entry_size) = unsafe {
+        (*((tag + 8) as *const u32) as usize, *((tag + 12) as *const u32) as usize)
+    };
+    if entry_size != mem::size_of::<SectionHeader>() {
+        return &[];
+    }
+
+    unsafe { slice::from_raw_parts((tag + 20) as *const SectionHeader, count) }
+}
+
+/// Name of a Rust symbol, it is printed demangled. The names that don't use the Rust mangling are
+/// printed as they are.
+pub struct Demangled<'a>(pub &'a str);
+
+impl<'a> fmt::Display for Demangled<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if !self.0.starts_with("_ZN") || !self.0.ends_with('E') {
+            return f.write_str(self.0);
+        }
+
+        // the path is a list of components prefixed by their length, the last one is a hash
+        let mut rest = &self.0[3..self.0.len() - 1];
+        let mut first = true;
+        while !rest.is_empty() {
+            let digits = rest.bytes().take_while(|&byte| byte >= b'0' && byte <= b'9').count();
+            let length = match rest[..digits].parse::<usize>() {
+                Ok(length) if digits + length <= rest.len() => length,
+                _ => return f.write_str(rest),
+            };
+            let component = &rest[digits..digits + length];
+            rest = &rest[digits + length..];
+
+            if rest.is_empty() && is_hash(component) {
+                break;
+            }
+            if !first {
+                f.write_str("::")?;
+            }
+            first = false;
+            write_component(f, component)?;
+        }
+        Ok(())
+    }
+}
+
+/// Check if a path component is the hash that ends the mangled names.
+fn is_hash(component: &str) -> bool {
+    component.len() == 17 && component.starts_with('h') &&
+    component[1..].chars().all(|c| c.is_digit(16))
+}
+
+/// Write a path component, replacing the escapes of the characters that can't be on symbols.
+fn write_component(f: &mut fmt::Formatter, component: &str) -> fmt::Result {
+    // the components that start with an escape are prefixed with an underscore
+    let mut rest = if component.starts_with("_$") {
+        &component[1..]
+    } else {
+        component
+    };
+
+    while !rest.is_empty() {
+        if rest.starts_with("..") {
+            f.write_str("::")?;
+            rest = &rest[2..];
+            continue;
+        }
+
+        if rest.starts_with('$') {
+            if let Some(end) = rest[1..].find('$') {
+                let escape = &rest[1..end + 1];
+                let character = match escape {
+                    "SP" => "@",
+                    "BP" => "*",
+                    "RF" => "&",
+                    "LT" => "<",
+                    "GT" => ">",
+                    "LP" => "(",
+                    "RP" => ")",
+                    "C" => ",",
+                    "u20" => " ",
+                    "u22" => "\"",
+                    "u27" => "'",
+                    "u2b" => "+",
+                    "u3b" => ";",
+                    "u5b" => "[",
+                    "u5d" => "]",
+                    "u7b" => "{",
+                    "u7d" => "}",
+                    "u7e" => "~",
+                    _ => &rest[..end + 2],
+                };
+                f.write_str(character)?;
+                rest = &rest[end + 2..];
+                continue;
+            }
+        }
+
+        let next = rest[1..]
+            .find(|c: char| c == '$' || c == '.')
+            .map_or(rest.len(), |next| next + 1);
+        f.write_str(&rest[..next])?;
+        rest = &rest[next..];
+    }
+    Ok(())
+}
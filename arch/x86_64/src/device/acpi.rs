@@ -0,0 +1,253 @@
+//! # ACPI Tables
+//!
+//! Locates the RSDP on the BIOS memory areas and maps the system description tables listed on the
+//! RSDT or the XSDT. The tables are mapped once on the MMIO area, since the firmware can place them
+//! outside of the memory covered by the direct map, and they stay mapped until `release_tables`.
+
+use collections::vec::Vec;
+use core::{mem, slice};
+use spin::Mutex;
+
+use memory::{self, CacheType, map_mmio, unmap_mmio};
+use memory::paging::{PhysicalAddress, VirtualAddress};
+
+/// Physical address of the pointer to the Extended BIOS Data Area segment.
+const EBDA_POINTER: PhysicalAddress = 0x40e;
+/// Number of bytes of the EBDA where the RSDP can be.
+const EBDA_SEARCH_SIZE: usize = 1024;
+/// BIOS read-only memory area where the RSDP can be.
+const BIOS_AREA_START: PhysicalAddress = 0xe0000;
+const BIOS_AREA_END: PhysicalAddress = 0x100000;
+/// Number of bytes of the RSDP covered by the checksum of ACPI 1.0.
+const RSDP_V1_SIZE: usize = 20;
+
+/// Root System Description Pointer.
+#[allow(dead_code)]
+#[derive(Clone, Copy)]
+#[repr(C, packed)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    // fields of ACPI 2.0 and later
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    reserved: [u8; 3],
+}
+
+/// Header shared by all the system description tables.
+#[repr(C, packed)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+impl SdtHeader {
+    /// Bytes of the table that follow the header.
+    pub fn data(&self) -> &[u8] {
+        let start = self as *const _ as usize + mem::size_of::<SdtHeader>();
+        let size = self.length as usize - mem::size_of::<SdtHeader>();
+        unsafe { slice::from_raw_parts(start as *const u8, size) }
+    }
+}
+
+/// A table mapped on the MMIO area.
+struct Mapping {
+    address: VirtualAddress,
+    size: usize,
+}
+
+impl Mapping {
+    fn header(&self) -> &SdtHeader {
+        unsafe { &*(self.address as *const SdtHeader) }
+    }
+}
+
+/// The system description tables listed on the root table.
+pub struct AcpiTables {
+    /// RSDT or XSDT
+    root: Mapping,
+    tables: Vec<Mapping>,
+}
+
+impl AcpiTables {
+    /// Find the table with the given signature.
+    pub fn find(&self, signature: &[u8; 4]) -> Option<&SdtHeader> {
+        self.tables
+            .iter()
+            .map(|table| table.header())
+            .find(|table| &table.signature == signature)
+    }
+}
+
+static TABLES: Mutex<Option<AcpiTables>> = Mutex::new(None);
+
+/// Find the RSDP and map the tables listed on the root table.
+pub fn init() {
+    let rsdp = match find_rsdp() {
+        Some(rsdp) => rsdp,
+        None => {
+            println!("ACPI: RSDP not found");
+            return;
+        }
+    };
+
+    // the XSDT replaces the RSDT from ACPI 2.0 on, the fields that point to it are covered by the
+    // extended checksum
+    let mut use_xsdt = rsdp.revision >= 2 && rsdp.xsdt_address != 0;
+    if use_xsdt && !extended_checksum(&rsdp) {
+        println!("ACPI: invalid extended checksum, using the RSDT");
+        use_xsdt = false;
+    }
+    let (address, entry_size) = if use_xsdt {
+        (rsdp.xsdt_address as PhysicalAddress, 8)
+    } else {
+        (rsdp.rsdt_address as PhysicalAddress, 4)
+    };
+
+    let root = match map_table(address) {
+        Some(root) => root,
+        None => {
+            println!("ACPI: invalid root table at {:#x}", address);
+            return;
+        }
+    };
+
+    let tables = root.header()
+        .data()
+        .chunks(entry_size)
+        .map(|entry| {
+            entry.iter().rev().fold(0, |address, &byte| address << 8 | byte as PhysicalAddress)
+        })
+        .filter_map(map_table)
+        .collect::<Vec<_>>();
+
+    println!("ACPI: revision {}, {} tables listed at {:#x}",
+             rsdp.revision,
+             tables.len(),
+             address);
+    *TABLES.lock() = Some(AcpiTables {
+        root: root,
+        tables: tables,
+    });
+}
+
+/// Call `f` with the ACPI tables, the tables can't be referenced after the call since they are
+/// unmapped by `release_tables`.
+///
+/// ## Returns
+/// The result of `f`, or `None` when the tables weren't found or were already released.
+pub fn with_tables<F, R>(f: F) -> Option<R>
+    where F: FnOnce(&AcpiTables) -> R
+{
+    TABLES.lock().as_ref().map(f)
+}
+
+/// Unmap the ACPI tables and give their memory back to the frame allocator. The tables can't be
+/// looked up afterwards, so it must only be called once all the drivers parsed the tables they
+/// need.
+pub fn release_tables() {
+    let tables = match TABLES.lock().take() {
+        Some(tables) => tables,
+        None => return,
+    };
+
+    for table in tables.tables.iter().chain(Some(&tables.root)) {
+        unmap_mmio(table.address, table.size);
+    }
+
+    memory::reclaim_acpi_memory();
+}
+
+/// Search the RSDP on the first KiB of the EBDA and on the BIOS read-only memory area.
+fn find_rsdp() -> Option<Rsdp> {
+    let ebda = map_physical(EBDA_POINTER, 2).map(|address| {
+        let segment = unsafe { *(address as *const u16) };
+        unmap_mmio(address, 2);
+        segment as PhysicalAddress * 16
+    });
+
+    ebda.and_then(|ebda| search_rsdp(ebda, ebda + EBDA_SEARCH_SIZE))
+        .or_else(|| search_rsdp(BIOS_AREA_START, BIOS_AREA_END))
+}
+
+/// Search the RSDP on the 16 bytes boundaries between `start` and `end`.
+fn search_rsdp(start: PhysicalAddress, end: PhysicalAddress) -> Option<Rsdp> {
+    // the RSDP on the last boundary extends past `end`
+    let size = end - start + mem::size_of::<Rsdp>();
+    let base = match map_physical(start, size) {
+        Some(base) => base,
+        None => return None,
+    };
+
+    let rsdp = (0..(end - start) / 16)
+        .map(|index| unsafe { &*((base + index * 16) as *const Rsdp) })
+        .find(|rsdp| {
+            let bytes = unsafe {
+                slice::from_raw_parts(*rsdp as *const _ as *const u8, RSDP_V1_SIZE)
+            };
+            &rsdp.signature == b"RSD PTR " && checksum(bytes)
+        })
+        .cloned();
+
+    unmap_mmio(base, size);
+    rsdp
+}
+
+/// Check the checksum of the whole RSDP of ACPI 2.0.
+fn extended_checksum(rsdp: &Rsdp) -> bool {
+    let bytes = unsafe {
+        slice::from_raw_parts(rsdp as *const _ as *const u8, mem::size_of::<Rsdp>())
+    };
+    rsdp.length as usize >= mem::size_of::<Rsdp>() && checksum(bytes)
+}
+
+/// Map the table at `address` and check its checksum.
+fn map_table(address: PhysicalAddress) -> Option<Mapping> {
+    // the header tells how much must be mapped
+    let header_size = mem::size_of::<SdtHeader>();
+    let length = match map_physical(address, header_size) {
+        Some(header) => {
+            let length = unsafe { (*(header as *const SdtHeader)).length as usize };
+            unmap_mmio(header, header_size);
+            length
+        }
+        None => return None,
+    };
+    if length < header_size {
+        return None;
+    }
+
+    map_physical(address, length).and_then(|table| {
+        let bytes = unsafe { slice::from_raw_parts(table as *const u8, length) };
+        if checksum(bytes) {
+            Some(Mapping {
+                address: table,
+                size: length,
+            })
+        } else {
+            unmap_mmio(table, length);
+            None
+        }
+    })
+}
+
+/// Map firmware memory with the caching type of the normal memory.
+fn map_physical(address: PhysicalAddress, size: usize) -> Option<VirtualAddress> {
+    map_mmio(address, size, CacheType::WriteBack)
+}
+
+/// Check that the bytes add up to zero.
+fn checksum(bytes: &[u8]) -> bool {
+    bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte)) == 0
+}
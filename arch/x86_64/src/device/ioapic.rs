@@ -0,0 +1,300 @@
+//! # I/O APIC
+//!
+//! The I/O APICs deliver the interrupts of the external devices to the Local APICs. They are
+//! discovered on the ACPI MADT, together with the interrupt source overrides that tell how the
+//! legacy ISA IRQs are wired to the global system interrupts (GSIs).
+
+use collections::vec::Vec;
+use core::intrinsics::{volatile_load, volatile_store};
+use spin::Mutex;
+
+use device::acpi;
+use memory::{CacheType, PAGE_SIZE, map_mmio};
+use memory::paging::{PhysicalAddress, VirtualAddress};
+
+/// I/O Register Select, selects the register accessed through `IOWIN`
+const IOREGSEL: usize = 0x00;
+/// I/O Window, data of the selected register
+const IOWIN: usize = 0x10;
+/// Version Register, it also holds the number of redirection entries
+const IOAPIC_REG_VERSION: u32 = 0x01;
+/// First register of the redirection table, each entry takes two registers
+const IOAPIC_REG_REDIRECTION: u32 = 0x10;
+
+/// MADT entry describing an I/O APIC
+const MADT_IOAPIC: u8 = 1;
+/// MADT entry describing an interrupt source override
+const MADT_SOURCE_OVERRIDE: u8 = 2;
+
+/// Redirection entry bits
+const REDIRECTION_ACTIVE_LOW: u64 = 1 << 13;
+const REDIRECTION_LEVEL: u64 = 1 << 15;
+const REDIRECTION_MASKED: u64 = 1 << 16;
+
+/// Number of legacy ISA IRQs.
+const ISA_IRQ_COUNT: u32 = 16;
+
+/// Polarity of an interrupt line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Polarity {
+    ActiveHigh,
+    ActiveLow,
+}
+
+/// Trigger mode of an interrupt line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TriggerMode {
+    Edge,
+    Level,
+}
+
+/// ISA IRQ that is connected to a different GSI or with a different polarity or trigger mode.
+#[derive(Debug, Clone, Copy)]
+struct SourceOverride {
+    irq: u8,
+    gsi: u32,
+    polarity: Polarity,
+    trigger_mode: TriggerMode,
+}
+
+impl SourceOverride {
+    /// Decode the MPS INTI flags of the override, the lines that conform to the bus
+    /// specification use the ISA defaults.
+    fn new(irq: u8, gsi: u32, flags: u16) -> SourceOverride {
+        SourceOverride {
+            irq: irq,
+            gsi: gsi,
+            polarity: if flags & 0b11 == 0b11 {
+                Polarity::ActiveLow
+            } else {
+                Polarity::ActiveHigh
+            },
+            trigger_mode: if (flags >> 2) & 0b11 == 0b11 {
+                TriggerMode::Level
+            } else {
+                TriggerMode::Edge
+            },
+        }
+    }
+}
+
+/// I/O APIC
+struct IoApic {
+    id: u8,
+    base: VirtualAddress,
+    /// First GSI handled by the I/O APIC
+    gsi_base: u32,
+    /// Number of redirection entries
+    gsi_count: u32,
+}
+
+impl IoApic {
+    /// Map the registers of the I/O APIC at the physical address `address`.
+    fn new(id: u8, address: PhysicalAddress, gsi_base: u32) -> Option<IoApic> {
+        map_mmio(address, PAGE_SIZE, CacheType::Uncacheable).map(|base| {
+            let mut ioapic = IoApic {
+                id: id,
+                base: base,
+                gsi_base: gsi_base,
+                gsi_count: 0,
+            };
+            ioapic.gsi_count = (ioapic.read(IOAPIC_REG_VERSION) >> 16 & 0xff) + 1;
+            ioapic
+        })
+    }
+
+    /// Read an I/O APIC register.
+    fn read(&self, reg: u32) -> u32 {
+        unsafe {
+            volatile_store((self.base + IOREGSEL) as *mut u32, reg);
+            volatile_load((self.base + IOWIN) as *const u32)
+        }
+    }
+
+    /// Change the value of an I/O APIC register.
+    fn write(&self, reg: u32, value: u32) {
+        unsafe {
+            volatile_store((self.base + IOREGSEL) as *mut u32, reg);
+            volatile_store((self.base + IOWIN) as *mut u32, value);
+        }
+    }
+
+    /// Check if the GSI is handled by this I/O APIC.
+    fn handles(&self, gsi: u32) -> bool {
+        gsi >= self.gsi_base && gsi < self.gsi_base + self.gsi_count
+    }
+
+    /// Read the redirection entry of a GSI.
+    fn entry(&self, gsi: u32) -> u64 {
+        let reg = IOAPIC_REG_REDIRECTION + (gsi - self.gsi_base) * 2;
+        (self.read(reg + 1) as u64) << 32 | self.read(reg) as u64
+    }
+
+    /// Change the redirection entry of a GSI. The entry is masked while it is being changed.
+    fn set_entry(&self, gsi: u32, value: u64) {
+        let reg = IOAPIC_REG_REDIRECTION + (gsi - self.gsi_base) * 2;
+        self.write(reg, REDIRECTION_MASKED as u32);
+        self.write(reg + 1, (value >> 32) as u32);
+        self.write(reg, value as u32);
+    }
+}
+
+lazy_static! {
+    static ref IOAPICS: Mutex<Vec<IoApic>> = Mutex::new(Vec::new());
+    static ref OVERRIDES: Mutex<Vec<SourceOverride>> = Mutex::new(Vec::new());
+}
+
+/// Discover the I/O APICs and the interrupt source overrides on the MADT. All the redirection
+/// entries start masked.
+///
+/// ## Returns
+/// `false` when there is no I/O APIC, the legacy PIC must be used instead.
+pub fn init() -> bool {
+    let found = acpi::with_tables(|tables| {
+        tables.find(b"APIC").map(|madt| parse_madt(madt.data())).is_some()
+    });
+    if found != Some(true) {
+        println!("IOAPIC: MADT not found");
+        return false;
+    }
+
+    let ioapics = IOAPICS.lock();
+    for ioapic in ioapics.iter() {
+        for gsi in ioapic.gsi_base..ioapic.gsi_base + ioapic.gsi_count {
+            ioapic.set_entry(gsi, REDIRECTION_MASKED);
+        }
+
+        println!("IOAPIC {}: GSIs {}-{}",
+                 ioapic.id,
+                 ioapic.gsi_base,
+                 ioapic.gsi_base + ioapic.gsi_count - 1);
+    }
+
+    !ioapics.is_empty()
+}
+
+/// Collect the I/O APICs and the interrupt source overrides of the MADT.
+fn parse_madt(madt: &[u8]) {
+    let mut ioapics = IOAPICS.lock();
+    let mut overrides = OVERRIDES.lock();
+
+    // the entries follow the Local APIC address and the flags
+    let mut offset = 8;
+    while offset + 2 <= madt.len() {
+        let entry_type = madt[offset];
+        let length = madt[offset + 1] as usize;
+        if length < 2 || offset + length > madt.len() {
+            break;
+        }
+        let entry = &madt[offset..offset + length];
+
+        match entry_type {
+            MADT_IOAPIC if length >= 12 => {
+                let address = read_field(entry, 4, 4) as PhysicalAddress;
+                match IoApic::new(entry[2], address, read_field(entry, 8, 4)) {
+                    Some(ioapic) => ioapics.push(ioapic),
+                    None => println!("IOAPIC: could not map the registers at {:#x}", address),
+                }
+            }
+            MADT_SOURCE_OVERRIDE if length >= 10 => {
+                let source_override = SourceOverride::new(entry[3],
+                                                          read_field(entry, 4, 4),
+                                                          read_field(entry, 8, 2) as u16);
+                debugln!("IOAPIC: {:?}", source_override);
+                overrides.push(source_override);
+            }
+            _ => {}
+        }
+
+        offset += length;
+    }
+}
+
+/// Check if there is any I/O APIC.
+pub fn is_available() -> bool {
+    !IOAPICS.lock().is_empty()
+}
+
+/// Get the GSI an ISA IRQ is connected to.
+pub fn isa_irq_to_gsi(irq: u8) -> u32 {
+    OVERRIDES.lock()
+        .iter()
+        .find(|source_override| source_override.irq == irq)
+        .map_or(irq as u32, |source_override| source_override.gsi)
+}
+
+/// Get the polarity and the trigger mode of a GSI. The ISA IRQs are edge triggered and active
+/// high unless they are overridden, the other GSIs are PCI lines, which are level triggered and
+/// active low.
+pub fn gsi_mode(gsi: u32) -> (Polarity, TriggerMode) {
+    let overrides = OVERRIDES.lock();
+    match overrides.iter().find(|source_override| source_override.gsi == gsi) {
+        Some(source_override) => (source_override.polarity, source_override.trigger_mode),
+        None if gsi < ISA_IRQ_COUNT => (Polarity::ActiveHigh, TriggerMode::Edge),
+        None => (Polarity::ActiveLow, TriggerMode::Level),
+    }
+}
+
+/// Deliver a GSI to the CPU with the given Local APIC ID with the given vector, using the polarity
+/// and the trigger mode of the line. The GSI is left unmasked.
+///
+/// ## Returns
+/// `false` when no I/O APIC handles the GSI.
+pub fn route_irq(gsi: u32, vector: u8, cpu: u8) -> bool {
+    let (polarity, trigger_mode) = gsi_mode(gsi);
+
+    // fixed delivery to a physical destination
+    let mut entry = vector as u64 | (cpu as u64) << 56;
+    if polarity == Polarity::ActiveLow {
+        entry |= REDIRECTION_ACTIVE_LOW;
+    }
+    if trigger_mode == TriggerMode::Level {
+        entry |= REDIRECTION_LEVEL;
+    }
+
+    with_ioapic(gsi, |ioapic| ioapic.set_entry(gsi, entry))
+}
+
+/// Stop the delivery of a GSI.
+///
+/// ## Returns
+/// `false` when no I/O APIC handles the GSI.
+pub fn mask(gsi: u32) -> bool {
+    with_ioapic(gsi, |ioapic| {
+        let entry = ioapic.entry(gsi);
+        ioapic.set_entry(gsi, entry | REDIRECTION_MASKED);
+    })
+}
+
+/// Resume the delivery of a GSI.
+///
+/// ## Returns
+/// `false` when no I/O APIC handles the GSI.
+pub fn unmask(gsi: u32) -> bool {
+    with_ioapic(gsi, |ioapic| {
+        let entry = ioapic.entry(gsi);
+        ioapic.set_entry(gsi, entry & !REDIRECTION_MASKED);
+    })
+}
+
+/// Call `f` with the I/O APIC that handles the GSI.
+///
+/// ## Returns
+/// `false` when no I/O APIC handles the GSI.
+fn with_ioapic<F>(gsi: u32, f: F) -> bool
+    where F: FnOnce(&IoApic)
+{
+    let ioapics = IOAPICS.lock();
+    match ioapics.iter().find(|ioapic| ioapic.handles(gsi)) {
+        Some(ioapic) => {
+            f(ioapic);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Read a little endian field of `size` bytes at `offset` of a MADT entry.
+fn read_field(entry: &[u8], offset: usize, size: usize) -> u32 {
+    entry[offset..offset + size].iter().rev().fold(0, |value, &byte| value << 8 | byte as u32)
+}
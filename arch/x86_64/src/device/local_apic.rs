@@ -2,10 +2,9 @@ use core::intrinsics::{volatile_load, volatile_store};
 use raw_cpuid::CpuId;
 use x86_64::registers::msr::*;
 
-use memory::{ActivePageTable, MemoryController, Frame};
-use memory::paging::Page;
-use memory::paging::{VirtualAddress, PhysicalAddress};
-use memory::paging::entry;
+use interrupts::{IPI_VECTOR, SPURIOUS_VECTOR, TIMER_VECTOR};
+use memory::{CacheType, PAGE_SIZE, map_mmio};
+use memory::paging::shootdown;
 
 /// Bind containing an instance of the LocalApic struct
 pub static mut LOCAL_APIC: LocalApic = LocalApic {
@@ -14,8 +13,8 @@ pub static mut LOCAL_APIC: LocalApic = LocalApic {
 };
 
 /// Initialize the Local APIC system
-pub unsafe fn init(memory_controller: &mut MemoryController) {
-    LOCAL_APIC.init(memory_controller);
+pub unsafe fn init() {
+    LOCAL_APIC.init();
 }
 
 /// End of interrupt register
@@ -30,6 +29,10 @@ const APIC_REG_TIMER_LOCAL_VECTOR: u32 = 0x320;
 const APIC_REG_TIMER_INIT_COUNT: u32 = 0x380;
 /// Timer Divide Configuration Register
 const APIC_REG_TIMER_DIVIDE: u32 = 0x3e0;
+/// Local APIC ID Register
+const APIC_REG_ID: u32 = 0x20;
+/// x2APIC ID Register
+const IA32_X2APIC_APICID: u32 = 0x802;
 
 /// Local APIC
 pub struct LocalApic {
@@ -39,20 +42,17 @@ pub struct LocalApic {
 
 impl LocalApic {
     /// Initialize the Local APIC system
-    pub fn init(&mut self, memory_controller: &mut MemoryController) {
+    pub fn init(&mut self) {
         // get the Local APIC base address
-        self.base = rdmsr(IA32_APIC_BASE) as usize & 0xFFFF0000;
+        let physical_base = rdmsr(IA32_APIC_BASE) as usize & 0xFFFF0000;
 
         // check if the x2APIC is supported
         self.x2_support = CpuId::new().get_feature_info().unwrap().has_x2apic();
 
+        // the x2APIC registers are accessed through MSRs, there is nothing to map
         if ! self.x2_support {
-            let page = Page::containing_address(self.base as VirtualAddress);
-            let frame = Frame::containing_address(self.base as PhysicalAddress);
-            memory_controller.map_to(page, frame, entry::PRESENT | entry::WRITABLE | entry::NO_EXECUTE);
-
-            // flush TLB
-            memory_controller.flush_all();
+            self.base = map_mmio(physical_base, PAGE_SIZE, CacheType::Uncacheable)
+                .expect("could not map the Local APIC registers");
         }
 
         self.init_ap();
@@ -60,7 +60,10 @@ impl LocalApic {
         // enable timer
         self.enable_timer();
 
-        println!("APIC: Initialized!\n\tBase address: 0x{:>016x}\n\tx2APIC support: {:#?}", self.base, self.x2_support);
+        // the CPU can now take part on TLB shootdowns
+        shootdown::cpu_online();
+
+        println!("APIC: Initialized!\n\tBase address: 0x{:>016x}\n\tx2APIC support: {:#?}", physical_base, self.x2_support);
     }
 
     /// Enable LAPIC.
@@ -70,9 +73,9 @@ impl LocalApic {
         unsafe {
             if self.x2_support {
                 wrmsr(IA32_APIC_BASE, rdmsr(IA32_APIC_BASE) | 1 << 10);
-                wrmsr(IA32_X2APIC_SIVR, 0x100);
+                wrmsr(IA32_X2APIC_SIVR, 0x100 | SPURIOUS_VECTOR as u64);
             } else {
-                self.write(0xf0, 0x100);
+                self.write(0xf0, 0x100 | SPURIOUS_VECTOR as u32);
             }
         }
     }
@@ -132,12 +135,21 @@ impl LocalApic {
         }
     }
 
+    /// Get the ID of the Local APIC, it identifies the current CPU.
+    pub fn id(&self) -> usize {
+        if self.x2_support {
+            unsafe { rdmsr(IA32_X2APIC_APICID) as usize }
+        } else {
+            (self.read(APIC_REG_ID) >> 24) as usize
+        }
+    }
+
     /// Throw an Inter-Processor Interrupt.
     ///
     /// ## Parameters
     /// - `apic_id`: LAPIC's ID of destination.
     pub fn inter_processor_interrupt(&mut self, apic_id: usize) {
-        let mut icr = 0x4040;
+        let mut icr = 0x4000 | IPI_VECTOR as u64;
 
         // Set the destination
         if self.x2_support {
@@ -173,7 +185,7 @@ impl LocalApic {
             wrmsr(APIC_REG_TIMER_INIT_COUNT, 0x10000);
 
             // Enable the time interrupt
-            wrmsr(APIC_REG_TIMER_LOCAL_VECTOR, (1<<17) | 0x40);
+            wrmsr(APIC_REG_TIMER_LOCAL_VECTOR, (1<<17) | TIMER_VECTOR as u64);
         }
     }
 }
@@ -1,13 +1,29 @@
-use memory::MemoryController;
-
+pub mod acpi;
+pub mod ioapic;
 pub mod local_apic;
+pub mod pic;
 pub mod rtc;
 pub mod serial;
 
 /// Initialize some devices
-pub fn init(memory_controller: &mut MemoryController) {
-    unsafe {
-        local_apic::init(memory_controller);
+pub fn init() {
+    use raw_cpuid::CpuId;
+
+    // the legacy IRQs must never land on the exception vectors
+    pic::init();
+
+    let has_apic = CpuId::new().get_feature_info().map_or(false, |info| info.has_apic());
+    if has_apic {
+        unsafe {
+            local_apic::init();
+        }
+    }
+
+    // the external interrupts are routed through the I/O APICs described by the ACPI tables, the
+    // PICs are only used when there are none
+    acpi::init();
+    if !has_apic || !ioapic::init() {
+        pic::enable();
     }
 }
 
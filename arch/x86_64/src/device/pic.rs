@@ -0,0 +1,156 @@
+//! # 8259 Programmable Interrupt Controller
+//!
+//! The legacy PICs deliver the ISA IRQs on vectors 0x08-0x0f by default, which collide with the
+//! CPU exceptions. They are always remapped to `VECTOR_BASE` and fully masked, so only spurious
+//! IRQs can reach the CPU. When there is no APIC the PICs are used to deliver the ISA IRQs instead.
+//!
+//! References:
+//! - http://wiki.osdev.org/8259_PIC
+
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+use x86_64::instructions::port::{inb, outb};
+
+/// First vector used by the PICs, the master uses the first eight vectors and the slave the
+/// next eight.
+pub const VECTOR_BASE: u8 = 0x20;
+
+/// Number of IRQs handled by the two PICs.
+pub const IRQ_COUNT: u8 = 16;
+
+/// IRQ of the master that is connected to the slave
+const CASCADE_IRQ: u8 = 2;
+
+const MASTER_COMMAND: u16 = 0x20;
+const MASTER_DATA: u16 = 0x21;
+const SLAVE_COMMAND: u16 = 0xa0;
+const SLAVE_DATA: u16 = 0xa1;
+
+/// Initialization command, the ICW4 is sent
+const ICW1_INIT: u8 = 0x11;
+/// 8086 mode
+const ICW4_8086: u8 = 0x01;
+/// End of interrupt command
+const OCW2_EOI: u8 = 0x20;
+/// Command to read the In-Service Register
+const OCW3_READ_ISR: u8 = 0x0b;
+
+/// Set when the PICs deliver the ISA IRQs.
+static ACTIVE: AtomicBool = AtomicBool::new(false);
+
+/// Mask of the two PICs, the slave on the upper byte. A set bit means that the IRQ is masked.
+static MASK: Mutex<u16> = Mutex::new(0xffff);
+
+/// Remap the PICs to `VECTOR_BASE` and mask all the IRQs.
+pub fn init() {
+    unsafe {
+        // start the initialization sequence
+        outb(MASTER_COMMAND, ICW1_INIT);
+        io_wait();
+        outb(SLAVE_COMMAND, ICW1_INIT);
+        io_wait();
+
+        // vector offsets
+        outb(MASTER_DATA, VECTOR_BASE);
+        io_wait();
+        outb(SLAVE_DATA, VECTOR_BASE + 8);
+        io_wait();
+
+        // tell the master that the slave is on the cascade IRQ, and the slave its identity
+        outb(MASTER_DATA, 1 << CASCADE_IRQ);
+        io_wait();
+        outb(SLAVE_DATA, CASCADE_IRQ);
+        io_wait();
+
+        outb(MASTER_DATA, ICW4_8086);
+        io_wait();
+        outb(SLAVE_DATA, ICW4_8086);
+        io_wait();
+    }
+
+    update_mask(|_| 0xffff);
+
+    println!("PIC: remapped to {:#x} and masked", VECTOR_BASE);
+}
+
+/// Use the PICs to deliver the ISA IRQs, it is used when there is no APIC. The IRQs stay masked
+/// until they are unmasked with `unmask`.
+pub fn enable() {
+    ACTIVE.store(true, Ordering::SeqCst);
+
+    // the IRQs of the slave go through the cascade IRQ
+    unmask(CASCADE_IRQ);
+
+    println!("PIC: delivering the ISA IRQs on vectors {:#x}-{:#x}",
+             VECTOR_BASE,
+             VECTOR_BASE + IRQ_COUNT - 1);
+}
+
+/// Check if the PICs deliver the ISA IRQs.
+pub fn is_active() -> bool {
+    ACTIVE.load(Ordering::Relaxed)
+}
+
+/// Stop the delivery of an IRQ.
+pub fn mask(irq: u8) {
+    update_mask(|mask| mask | 1 << irq);
+}
+
+/// Resume the delivery of an IRQ.
+pub fn unmask(irq: u8) {
+    update_mask(|mask| mask & !(1 << irq));
+}
+
+/// Acknowledge an IRQ, the IRQs of the slave must be acknowledged on both PICs.
+pub fn end_of_interrupt(irq: u8) {
+    unsafe {
+        if irq >= 8 {
+            outb(SLAVE_COMMAND, OCW2_EOI);
+        }
+        outb(MASTER_COMMAND, OCW2_EOI);
+    }
+}
+
+/// Check if an IRQ is spurious. The PICs raise IRQ 7 or IRQ 15 when an IRQ goes away before it is
+/// acknowledged, in that case the IRQ isn't set on the In-Service Register and it must not be
+/// acknowledged. The master still expects an end of interrupt for a spurious IRQ of the slave,
+/// it is sent here.
+pub fn is_spurious(irq: u8) -> bool {
+    let (command, line) = match irq {
+        7 => (MASTER_COMMAND, 7),
+        15 => (SLAVE_COMMAND, 7),
+        _ => return false,
+    };
+
+    let in_service = unsafe {
+        outb(command, OCW3_READ_ISR);
+        inb(command)
+    };
+
+    if in_service & 1 << line != 0 {
+        return false;
+    }
+
+    if irq == 15 {
+        unsafe { outb(MASTER_COMMAND, OCW2_EOI) };
+    }
+    true
+}
+
+/// Change the mask of the two PICs.
+fn update_mask<F>(f: F)
+    where F: FnOnce(u16) -> u16
+{
+    let mut mask = MASK.lock();
+    *mask = f(*mask);
+
+    unsafe {
+        outb(MASTER_DATA, *mask as u8);
+        outb(SLAVE_DATA, (*mask >> 8) as u8);
+    }
+}
+
+/// Wait for the PIC to process a command, writing to an unused port takes long enough.
+fn io_wait() {
+    unsafe { outb(0x80, 0) };
+}
@@ -1,138 +1,267 @@
-use x86_64::structures::idt::{ExceptionStackFrame, PageFaultErrorCode};
+//! # CPU Exceptions
+//!
+//! All the exceptions enter the kernel through the common entry of `interrupt_stubs.asm`, which
+//! saves the registers on a `TrapFrame`. Page faults on the reserved regions of the current
+//! address space are resolved, and the debug traps are resumed. The other exceptions are reported
+//! with a full register and stack dump, plus a backtrace when they come from the kernel, and
+//! handled by a `FaultPolicy`: faults of user code kill the faulting thread or are forwarded to a
+//! user space pager, faults of the kernel panic.
 
-/// Handler for the division by zero exception
-pub extern "x86-interrupt" fn divide_by_zero(stack_frame: &mut ExceptionStackFrame) {
-    // print out the error message and the stack_frame
-    println!("\nDivide by zero fault at {:>02x}:{:>016x}", stack_frame.code_segment, stack_frame.instruction_pointer);
-    loop {}
-}
+use core::fmt;
+use spin::Once;
+use x86_64::structures::idt::PageFaultErrorCode;
 
-/// Handler for a debug exception
-pub extern "x86-interrupt" fn debug(stack_frame: &mut ExceptionStackFrame) {
-    println!("\nDebug trap at {:>02x}:{:>016x}", stack_frame.code_segment, stack_frame.instruction_pointer);
-    loop {}
-}
+use backtrace;
+use memory;
+use memory::paging::{Mapper, VirtualAddress};
+use super::TrapFrame;
 
-/// Handler for a non-maskable interrupt exception
-pub extern "x86-interrupt" fn non_maskable(stack_frame: &mut ExceptionStackFrame) {
-    println!("\nNon-maskable interrupt at {:>02x}:{:>016x}", stack_frame.code_segment, stack_frame.instruction_pointer);
-    loop {}
-}
+const DEBUG: u8 = 1;
+const NON_MASKABLE_INTERRUPT: u8 = 2;
+const BREAKPOINT: u8 = 3;
+const DOUBLE_FAULT: u8 = 8;
+const INVALID_TSS: u8 = 10;
+const SEGMENT_NOT_PRESENT: u8 = 11;
+const STACK_SEGMENT_FAULT: u8 = 12;
+const GENERAL_PROTECTION_FAULT: u8 = 13;
+const PAGE_FAULT: u8 = 14;
+const MACHINE_CHECK: u8 = 18;
 
-/// Handler to catch breakpoint exceptions
-pub extern "x86-interrupt" fn breakpoint(stack_frame: &mut ExceptionStackFrame) {
-    println!("\nBreakpoint trap at {:>02x}:{:>016x}", stack_frame.code_segment, stack_frame.instruction_pointer);
-}
+/// Names of the exceptions, indexed by vector
+const NAMES: [&'static str; 32] = ["Divide by zero fault",
+                                   "Debug trap",
+                                   "Non-maskable interrupt",
+                                   "Breakpoint trap",
+                                   "Overflow trap",
+                                   "Bound range exceeded fault",
+                                   "Invalid opcode fault",
+                                   "Device not available fault",
+                                   "Double fault",
+                                   "Coprocessor segment overrun",
+                                   "Invalid TSS fault",
+                                   "Segment not present fault",
+                                   "Stack segment fault",
+                                   "Protection fault",
+                                   "Page fault",
+                                   "Reserved exception 15",
+                                   "FPU floating point fault",
+                                   "Alignment check fault",
+                                   "Machine check fault",
+                                   "SIMD floating point fault",
+                                   "Virtualization fault",
+                                   "Control protection fault",
+                                   "Reserved exception 22",
+                                   "Reserved exception 23",
+                                   "Reserved exception 24",
+                                   "Reserved exception 25",
+                                   "Reserved exception 26",
+                                   "Reserved exception 27",
+                                   "Hypervisor injection exception",
+                                   "VMM communication exception",
+                                   "Security exception",
+                                   "Reserved exception 31"];
 
-/// Handler for a overflow exception
-pub extern "x86-interrupt" fn overflow(stack_frame: &mut ExceptionStackFrame) {
-    println!("\nOverflow trap at {:>02x}:{:>016x}", stack_frame.code_segment, stack_frame.instruction_pointer);
-    loop {}
-}
+/// Number of stack entries printed by the dump
+const STACK_DUMP_ENTRIES: usize = 16;
 
-/// Handler for a bound range exceeded exception
-pub extern "x86-interrupt" fn bound_range_exceeded(stack_frame: &mut ExceptionStackFrame) {
-    println!("\nBound range exceeded fault at {:>02x}:{:>016x}", stack_frame.code_segment, stack_frame.instruction_pointer);
-    loop {}
+/// What is done with an exception that the kernel couldn't resolve.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FaultPolicy {
+    /// Terminate the user thread that raised the exception
+    KillThread,
+    /// Let the pager of the current address space resolve a user page fault
+    ForwardToPager,
+    /// Stop the kernel, the fault comes from the kernel itself
+    Panic,
 }
 
-/// Handler for a invalid opcode exception
-pub extern "x86-interrupt" fn invalid_opcode(stack_frame: &mut ExceptionStackFrame) {
-    println!("\nInvalid opcode fault at {:>02x}:{:>016x}", stack_frame.code_segment, stack_frame.instruction_pointer);
-    loop {}
+/// Handlers of the faults of user code, they are installed by the scheduler. Until then the faults
+/// of user code panic like the faults of the kernel.
+pub struct UserFaultHandlers {
+    /// Terminate the thread that was running. It changes the frame so that another thread resumes.
+    pub kill_thread: fn(frame: &mut TrapFrame),
+    /// Hand a page fault to the pager of the current address space, the thread must not resume
+    /// until the pager resolves it. Returns `false` when the address space has no pager.
+    pub forward_to_pager: fn(frame: &mut TrapFrame, address: VirtualAddress) -> bool,
 }
 
-/// Handler for a device not found exception
-pub extern "x86-interrupt" fn device_not_available(stack_frame: &mut ExceptionStackFrame) {
-    println!("\nDevice not available fault at {:>02x}:{:>016x}", stack_frame.code_segment, stack_frame.instruction_pointer);
-    loop {}
-}
+static USER_FAULT_HANDLERS: Once<UserFaultHandlers> = Once::new();
 
-/// Handler to catch double faults
-pub extern "x86-interrupt" fn double_fault(stack_frame: &mut ExceptionStackFrame, error_code: u64) {
-    println!("\nDouble fault: {:x} at {:>02x}:{:>016x}", error_code, stack_frame.code_segment,
-             stack_frame.instruction_pointer);
-    loop {}
+/// Install the handlers of the faults of user code, only the first call has any effect.
+pub fn set_user_fault_handlers(handlers: UserFaultHandlers) {
+    USER_FAULT_HANDLERS.call_once(|| handlers);
 }
 
-/// Handler for a invalid tss exception
-pub extern "x86-interrupt" fn invalid_tss(stack_frame: &mut ExceptionStackFrame, error_code: u64) {
-    println!("\nInvalid TSS fault: {:x} at {:>02x}:{:>016x}", error_code, stack_frame.code_segment,
-             stack_frame.instruction_pointer);
-    loop {}
-}
+/// Handle an exception, it is called by `interrupt_dispatch` for the vectors below 32.
+pub fn dispatch(frame: &mut TrapFrame) {
+    let vector = frame.vector as u8;
+    let name = NAMES[vector as usize];
 
-/// Handler for a segment not present exception
-pub extern "x86-interrupt" fn segment_not_present(stack_frame: &mut ExceptionStackFrame, error_code: u64) {
-    println!("\nSegment not present fault: {:x} at {:>02x}:{:>016x}", error_code, stack_frame.code_segment,
-             stack_frame.instruction_pointer);
-    loop {}
+    match vector {
+        DEBUG | BREAKPOINT => {
+            println!("\n{} at {:>02x}:{:>016x}", name, frame.cs, frame.rip);
+            return;
+        }
+        NON_MASKABLE_INTERRUPT => {
+            println!("\n{} at {:>02x}:{:>016x}\n{}", name, frame.cs, frame.rip, frame);
+            return;
+        }
+        PAGE_FAULT => {
+            let error_code = PageFaultErrorCode::from_bits_truncate(frame.error_code);
+            if memory::handle_page_fault(fault_address(), error_code) {
+                return;
+            }
+        }
+        _ => {}
+    }
+
+    let policy = policy(frame);
+    if policy == FaultPolicy::ForwardToPager {
+        let handlers = USER_FAULT_HANDLERS.try().expect("no user fault handlers");
+        if (handlers.forward_to_pager)(frame, fault_address()) {
+            return;
+        }
+    }
+
+    println!("\n{} at {:>02x}:{:>016x}", name, frame.cs, frame.rip);
+    if has_error_code(vector) {
+        println!("error code: {:#x} ({})",
+                 frame.error_code,
+                 ErrorCode {
+                     vector: vector,
+                     code: frame.error_code,
+                 });
+    }
+    // a page fault on the guard page of the interrupted stack can't push its frame, so it ends up
+    // as a double fault, on its own stack
+    if vector == PAGE_FAULT || vector == DOUBLE_FAULT {
+        check_stack_overflow(fault_address());
+    }
+    println!("{}", frame);
+    dump_stack(frame);
+    if !frame.is_user() {
+        backtrace::print_from(frame.rip as usize, frame.rbp as usize);
+    }
+
+    match policy {
+        FaultPolicy::Panic => panic!("{} at {:#x}", name, frame.rip),
+        _ => {
+            let handlers = USER_FAULT_HANDLERS.try().expect("no user fault handlers");
+            println!("killing the faulting thread");
+            (handlers.kill_thread)(frame);
+        }
+    }
 }
 
-/// Handler for a stack segment fault
-pub extern "x86-interrupt" fn stack_segment_fault(stack_frame: &mut ExceptionStackFrame, error_code: u64) {
-    println!("\nStack segment fault: {:x} at {:>02x}:{:>016x}", error_code, stack_frame.code_segment,
-             stack_frame.instruction_pointer);
-    loop {}
+/// Choose what is done with an exception that couldn't be resolved.
+fn policy(frame: &TrapFrame) -> FaultPolicy {
+    let vector = frame.vector as u8;
+
+    // the machine checks and the double faults can't be recovered even when they come from user
+    // code, and the user faults can't be handled until there is a scheduler
+    if !frame.is_user() || vector == DOUBLE_FAULT || vector == MACHINE_CHECK ||
+       USER_FAULT_HANDLERS.try().is_none() {
+        return FaultPolicy::Panic;
+    }
+
+    match vector {
+        PAGE_FAULT => FaultPolicy::ForwardToPager,
+        _ => FaultPolicy::KillThread,
+    }
 }
 
-/// Handler for a general protection fault
-pub extern "x86-interrupt" fn general_protection_fault(stack_frame: &mut ExceptionStackFrame, error_code: u64) {
-    println!("\nProtection fault: {:x} at {:>02x}:{:>016x}", error_code, stack_frame.code_segment,
-             stack_frame.instruction_pointer);
-    loop {}
+/// Check if the CPU pushes an error code for an exception, it must match the stubs of
+/// `interrupt_stubs.asm`.
+fn has_error_code(vector: u8) -> bool {
+    match vector {
+        8 | 10...14 | 17 | 21 | 29 | 30 => true,
+        _ => false,
+    }
 }
 
-/// Handler for page faults
-pub extern "x86-interrupt" fn page_fault(stack_frame: &mut ExceptionStackFrame, error_code: PageFaultErrorCode) {
+/// Address that caused the last page fault.
+fn fault_address() -> VirtualAddress {
     use x86_64::registers::control_regs;
 
-    println!("\nPage fault while accessing {:>015x}\nerror code: {:?} at {:>02x}:{:>016x}",
-             control_regs::cr2(),
-             error_code,
-             stack_frame.code_segment,
-             stack_frame.instruction_pointer);
-    loop {}
+    control_regs::cr2().0
 }
 
-/// Handler for a FPU floating point fault
-pub extern "x86-interrupt" fn x87_floating_point(stack_frame: &mut ExceptionStackFrame) {
-    println!("\nFPU floating point fault at {:>02x}:{:>016x}", stack_frame.code_segment,
-             stack_frame.instruction_pointer);
-    loop {}
+/// Error code of an exception, it is printed decoded.
+struct ErrorCode {
+    vector: u8,
+    code: u64,
 }
 
-/// Handler for alignment check exception
-pub extern "x86-interrupt" fn alignment_check(stack_frame: &mut ExceptionStackFrame, error_code: u64) {
-    println!("\nAlignment check fault: {:x} at {:>02x}:{:>016x}", error_code, stack_frame.code_segment,
-             stack_frame.instruction_pointer);
-    loop {}
-}
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.vector {
+            PAGE_FAULT => {
+                write!(f,
+                       "{} {} from {} mode at {:#x}",
+                       if self.code & 1 << 0 != 0 { "protection violation" } else { "page not present" },
+                       if self.code & 1 << 4 != 0 {
+                           "on instruction fetch"
+                       } else if self.code & 1 << 1 != 0 {
+                           "on write"
+                       } else {
+                           "on read"
+                       },
+                       if self.code & 1 << 2 != 0 { "user" } else { "kernel" },
+                       fault_address())?;
+                if self.code & 1 << 3 != 0 {
+                    write!(f, ", reserved bit set")?;
+                }
+                if self.code & 1 << 5 != 0 {
+                    write!(f, ", protection key")?;
+                }
+                Ok(())
+            }
+            INVALID_TSS | SEGMENT_NOT_PRESENT | STACK_SEGMENT_FAULT | GENERAL_PROTECTION_FAULT => {
+                if self.code == 0 {
+                    return write!(f, "not segment related");
+                }
 
-/// Handler for a machine check exception
-pub extern "x86-interrupt" fn machine_check(stack_frame: &mut ExceptionStackFrame) {
-    println!("\nMachine check fault at {:>02x}:{:>016x}", stack_frame.code_segment,
-             stack_frame.instruction_pointer);
-    loop {}
+                let table = match (self.code >> 1) & 0b11 {
+                    0 => "GDT",
+                    1 | 3 => "IDT",
+                    _ => "LDT",
+                };
+                write!(f, "{} entry {:#x}", table, (self.code >> 3) & 0x1fff)?;
+                if self.code & 1 != 0 {
+                    write!(f, ", external event")?;
+                }
+                Ok(())
+            }
+            _ => write!(f, "no decoding"),
+        }
+    }
 }
 
-/// Handler for a SIMD floating point exception
-pub extern "x86-interrupt" fn simd_floating_point(stack_frame: &mut ExceptionStackFrame) {
-    println!("\nSIMD floating point fault at {:>02x}:{:>016x}", stack_frame.code_segment,
-             stack_frame.instruction_pointer);
-    loop {}
-}
+/// Print the top of the interrupted stack. The stacks of user code aren't accessed by the kernel.
+fn dump_stack(frame: &TrapFrame) {
+    if frame.is_user() {
+        return;
+    }
 
-/// Handler for a virtualization exception
-pub extern "x86-interrupt" fn virtualization(stack_frame: &mut ExceptionStackFrame) {
-    println!("\nVirtualization fault at {:>02x}:{:>016x}", stack_frame.code_segment,
-             stack_frame.instruction_pointer);
-    loop {}
+    println!("Stack:");
+    let mapper = unsafe { Mapper::new() };
+    for entry in 0..STACK_DUMP_ENTRIES {
+        let address = frame.rsp as usize + entry * 8;
+        if mapper.translate(address).is_none() {
+            println!("{:>016x}: not mapped", address);
+            break;
+        }
+        println!("{:>016x}: {:>016x}", address, unsafe { *(address as *const u64) });
+    }
 }
 
-/// Handler for a security exception
-pub extern "x86-interrupt" fn security_exception(stack_frame: &mut ExceptionStackFrame, error_code: u64) {
-    println!("\nSecurity exception: {:x} at {:>02x}:{:>016x}", error_code, stack_frame.code_segment,
-             stack_frame.instruction_pointer);
-    loop {}
+/// Report a stack overflow if `address` is on the guard page of a kernel stack.
+fn check_stack_overflow(address: VirtualAddress) {
+    if let Some(stack) = memory::guard_page_owner(address) {
+        println!("Kernel stack overflow on the {} stack ({:#x}-{:#x}) accessing {:>015x}",
+                 stack.name,
+                 stack.bottom,
+                 stack.top,
+                 address);
+    }
 }
@@ -1,8 +1,9 @@
-use x86_64::structures::idt::ExceptionStackFrame;
-
-use device::local_apic::LOCAL_APIC;
+use memory::paging::shootdown;
 
 /// Handler for a Inter-Process Interrupt (IPI)
-pub extern "x86-interrupt" fn ipi(stack_frame: &mut ExceptionStackFrame) {
-    unsafe { LOCAL_APIC.end_of_interrupt(); }
+pub fn ipi(_context: usize) -> bool {
+    // invalidate the TLB entries requested by other CPUs
+    shootdown::handle_ipi();
+
+    true
 }
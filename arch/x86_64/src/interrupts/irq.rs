@@ -1,9 +1,7 @@
-use x86_64::structures::idt::ExceptionStackFrame;
-
 use time;
-use device::local_apic;
 
-pub extern "x86-interrupt" fn timer(stack_frame: &mut ExceptionStackFrame) {
+/// Handler for the Local APIC timer.
+pub fn timer(_context: usize) -> bool {
     const UPDATE_RATE: u64 = 0x10000;
 
     let mut offset = time::OFFSET.lock();
@@ -11,8 +9,6 @@ pub extern "x86-interrupt" fn timer(stack_frame: &mut ExceptionStackFrame) {
     offset.1 = sum % 1000000000;
     offset.0 += sum / 1000000000;
 
-    unsafe {
-        local_apic::LOCAL_APIC.end_of_interrupt();
-    }
-
+    true
 }
+
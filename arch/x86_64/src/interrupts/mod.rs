@@ -1,18 +1,41 @@
 //! # Exception handler system
 
 use memory::MemoryController;
-use x86_64::PrivilegeLevel;
 use x86_64::structures::tss::TaskStateSegment;
 use x86_64::structures::idt::Idt;
 use spin::Once;
 
+pub use self::exceptions::{FaultPolicy, UserFaultHandlers, set_user_fault_handlers};
+pub use self::trap_frame::TrapFrame;
+
+pub use self::vectors::{IrqHandler, FIRST_VECTOR, TIMER_VECTOR, IPI_VECTOR, SPURIOUS_VECTOR};
+pub use self::vectors::{allocate_vector, free_vector, register_irq_handler};
+pub use self::vectors::{unregister_irq_handler, register_isa_irq_handler};
+
 mod gdt;
 mod ipi;
 mod irq;
 mod exceptions;
+mod trap_frame;
+mod vectors;
 
+/// The double fault handler runs on its own stack. A page fault on the guard page of a kernel
+/// stack can't push its frame and escalates to a double fault, which reports the overflow. The page
+/// faults stay on the interrupted stack, so a nested page fault can't overwrite the frame of the
+/// outer one.
 const DOUBLE_FAULT_IST_INDEX: usize = 0;
 
+extern {
+    /// Entry stubs of all the vectors, defined on `interrupt_stubs.asm`
+    static interrupt_stubs: [usize; 256];
+}
+
+/// Get the entry stub of a vector as a handler of the IDT. The stubs save the registers
+/// themselves, so they are installed as they are whatever the handler type of the entry.
+unsafe fn stub<F>(vector: usize) -> F {
+    ::core::mem::transmute_copy(&interrupt_stubs[vector])
+}
+
 // The IDT is allocated statically to ensure that this stays in memory until the end of the kernel
 // execution.
 lazy_static! {
@@ -20,46 +43,64 @@ lazy_static! {
         // create a new IDT structure
         let mut idt = Idt::new();
 
-        // Set up exceptions
-        idt.divide_by_zero.set_handler_fn(exceptions::divide_by_zero);
-        idt.debug.set_handler_fn(exceptions::debug);
-        idt.non_maskable_interrupt.set_handler_fn(exceptions::non_maskable);
-        idt.overflow.set_handler_fn(exceptions::overflow);
-        idt.breakpoint.set_handler_fn(exceptions::breakpoint);
-        idt.bound_range_exceeded.set_handler_fn(exceptions::bound_range_exceeded);
-        idt.invalid_opcode.set_handler_fn(exceptions::invalid_opcode);
-        idt.device_not_available.set_handler_fn(exceptions::device_not_available);
+        // Set up exceptions, they are all handled by `exceptions::dispatch`
         unsafe {
-            idt.double_fault.set_handler_fn(exceptions::double_fault)
+            idt.divide_by_zero.set_handler_fn(stub(0));
+            idt.debug.set_handler_fn(stub(1));
+            idt.non_maskable_interrupt.set_handler_fn(stub(2));
+            idt.breakpoint.set_handler_fn(stub(3));
+            idt.overflow.set_handler_fn(stub(4));
+            idt.bound_range_exceeded.set_handler_fn(stub(5));
+            idt.invalid_opcode.set_handler_fn(stub(6));
+            idt.device_not_available.set_handler_fn(stub(7));
+            idt.double_fault.set_handler_fn(stub(8))
                 .set_stack_index(DOUBLE_FAULT_IST_INDEX as u16);
+            // 9 the coprocessor_segment_overrun is a discontinued exception
+            idt.invalid_tss.set_handler_fn(stub(10));
+            idt.segment_not_present.set_handler_fn(stub(11));
+            idt.stack_segment_fault.set_handler_fn(stub(12));
+            idt.general_protection_fault.set_handler_fn(stub(13));
+            idt.page_fault.set_handler_fn(stub(14));
+            // 15 reserved
+            idt.x87_floating_point.set_handler_fn(stub(16));
+            idt.alignment_check.set_handler_fn(stub(17));
+            idt.machine_check.set_handler_fn(stub(18));
+            idt.simd_floating_point.set_handler_fn(stub(19));
+            idt.virtualization.set_handler_fn(stub(20));
+            // 21 through 29 reserved
+            idt.security_exception.set_handler_fn(stub(30));
+            // 31 reserved
+        }
+
+        // the other vectors are handled by `vectors::irq_dispatch`, their handlers are registered
+        // at runtime
+        for (index, entry) in idt.interrupts.iter_mut().enumerate() {
+            entry.set_handler_fn(unsafe { stub(FIRST_VECTOR as usize + index) });
         }
-        // 9 the coprocessor_segment_overrun is a discontinued exception
-        idt.invalid_tss.set_handler_fn(exceptions::invalid_tss);
-        idt.segment_not_present.set_handler_fn(exceptions::segment_not_present);
-        idt.stack_segment_fault.set_handler_fn(exceptions::stack_segment_fault);
-        idt.general_protection_fault.set_handler_fn(exceptions::general_protection_fault);
-        idt.page_fault.set_handler_fn(exceptions::page_fault);
-        // 15 reserved
-        idt.x87_floating_point.set_handler_fn(exceptions::x87_floating_point);
-        idt.alignment_check.set_handler_fn(exceptions::alignment_check);
-        idt.machine_check.set_handler_fn(exceptions::machine_check);
-        idt.simd_floating_point.set_handler_fn(exceptions::simd_floating_point);
-        idt.virtualization.set_handler_fn(exceptions::virtualization);
-        // 21 through 29 reserved
-        idt.security_exception.set_handler_fn(exceptions::security_exception);
-        // 31 reserved
-
-        // set timer interrupt
-        idt.interrupts[0x40].set_handler_fn(irq::timer).set_privilege_level(PrivilegeLevel::Ring3);
-
-        // set IPI handler
-        // TODO implement this properly. For this this is just a null interrupt.
-        idt.interrupts[64].set_handler_fn(ipi::ipi);
 
         idt
     };
 }
 
+/// Common handler of all the vectors, it is called by the entry stubs of `interrupt_stubs.asm`
+/// with the registers of the interrupted code.
+#[no_mangle]
+pub extern "C" fn interrupt_dispatch(frame: &mut TrapFrame) {
+    // the stubs push the vector as a full qword
+    let vector = frame.vector as u8;
+
+    if vector < FIRST_VECTOR {
+        exceptions::dispatch(frame);
+    } else {
+        vectors::irq_dispatch(vector);
+    }
+}
+
+/// Halt the CPU until the next interrupt arrives.
+pub fn halt() {
+    unsafe { asm!("hlt" :::: "volatile") };
+}
+
 static TSS: Once<TaskStateSegment> = Once::new();
 static GDT: Once<gdt::Gdt> = Once::new();
 
@@ -70,8 +111,8 @@ pub fn init(memory_controller: &mut MemoryController) {
     use x86_64::VirtualAddress;
     use x86_64::structures::gdt::SegmentSelector;
 
-    // allocate a double fault stack
-    let double_fault_stack = memory_controller.alloc_stack(1).expect("could not allocate double fault stack");
+    // allocate the double fault stack, the register dump and the backtrace need more than a page
+    let double_fault_stack = memory_controller.alloc_stack(2, "double fault IST").expect("could not allocate double fault stack");
 
     // configure the task state segment
     let tss = TSS.call_once(|| {
@@ -101,4 +142,7 @@ pub fn init(memory_controller: &mut MemoryController) {
 
     // load the IDT table into the CPU
     IDT.load();
+
+    register_irq_handler(TIMER_VECTOR, irq::timer, 0);
+    register_irq_handler(IPI_VECTOR, ipi::ipi, 0);
 }
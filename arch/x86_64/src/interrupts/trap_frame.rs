@@ -0,0 +1,92 @@
+//! # Trap Frame
+//!
+//! State of the interrupted code saved by the common entry of `interrupt_stubs.asm`.
+
+use core::fmt;
+
+/// Registers saved on the stack when an interrupt or an exception enters the kernel. The layout
+/// must match the order in which `interrupt_stubs.asm` pushes the registers.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct TrapFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    /// Vector of the interrupt
+    pub vector: u64,
+    /// Error code pushed by the CPU, zero for the vectors without one
+    pub error_code: u64,
+    // interrupt stack frame pushed by the CPU
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+impl TrapFrame {
+    /// Check if the interrupted code was running on user mode.
+    pub fn is_user(&self) -> bool {
+        self.cs & 0b11 == 3
+    }
+}
+
+/// Control registers of the current CPU.
+struct ControlRegisters {
+    cr0: u64,
+    cr2: u64,
+    cr3: u64,
+    cr4: u64,
+}
+
+impl ControlRegisters {
+    fn read() -> ControlRegisters {
+        let (cr0, cr2, cr3, cr4): (u64, u64, u64, u64);
+        unsafe {
+            asm!("mov %cr0, $0" : "=r"(cr0));
+            asm!("mov %cr2, $0" : "=r"(cr2));
+            asm!("mov %cr3, $0" : "=r"(cr3));
+            asm!("mov %cr4, $0" : "=r"(cr4));
+        }
+
+        ControlRegisters {
+            cr0: cr0,
+            cr2: cr2,
+            cr3: cr3,
+            cr4: cr4,
+        }
+    }
+}
+
+/// Register dump, the control registers are read when it is printed.
+impl fmt::Display for TrapFrame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let control = ControlRegisters::read();
+
+        writeln!(f, "RAX {:016x} RBX {:016x} RCX {:016x}", self.rax, self.rbx, self.rcx)?;
+        writeln!(f, "RDX {:016x} RSI {:016x} RDI {:016x}", self.rdx, self.rsi, self.rdi)?;
+        writeln!(f, "RBP {:016x} RSP {:016x} R8  {:016x}", self.rbp, self.rsp, self.r8)?;
+        writeln!(f, "R9  {:016x} R10 {:016x} R11 {:016x}", self.r9, self.r10, self.r11)?;
+        writeln!(f, "R12 {:016x} R13 {:016x} R14 {:016x}", self.r12, self.r13, self.r14)?;
+        writeln!(f, "R15 {:016x} RIP {:016x} RFL {:016x}", self.r15, self.rip, self.rflags)?;
+        writeln!(f, "CS  {:04x} SS  {:04x}", self.cs, self.ss)?;
+        write!(f,
+               "CR0 {:016x} CR2 {:016x} CR3 {:016x} CR4 {:016x}",
+               control.cr0,
+               control.cr2,
+               control.cr3,
+               control.cr4)
+    }
+}
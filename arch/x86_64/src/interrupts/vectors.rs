@@ -0,0 +1,218 @@
+//! # Interrupt Vectors
+//!
+//! The vectors 32-255 enter the kernel through the stubs of `interrupt_stubs.asm`, which reach
+//! `irq_dispatch` with the vector. Drivers claim a vector with `allocate_vector` and attach their
+//! handlers to it with `register_irq_handler`. A vector can be shared by several handlers, all of
+//! them run on each interrupt and the interrupt is acknowledged once they are done.
+
+use collections::vec::Vec;
+use spin::{Mutex, RwLock};
+
+use device::{ioapic, pic};
+use device::local_apic::LOCAL_APIC;
+
+/// First vector that isn't used by the CPU exceptions.
+pub const FIRST_VECTOR: u8 = 32;
+
+/// Vector of the Local APIC timer.
+pub const TIMER_VECTOR: u8 = 0x40;
+
+/// Vector of the Inter-Processor Interrupts.
+pub const IPI_VECTOR: u8 = 0xf0;
+
+/// Vector of the Local APIC spurious interrupts, they must not be acknowledged.
+pub const SPURIOUS_VECTOR: u8 = 0xff;
+
+/// Number of vectors that can have handlers.
+pub const VECTOR_COUNT: usize = 256 - FIRST_VECTOR as usize;
+
+/// Interrupt handler, it is called with the context given on registration.
+///
+/// ## Returns
+/// `true` if the interrupt was raised by the device of the handler. On shared vectors the other
+/// handlers still run.
+pub type IrqHandler = fn(context: usize) -> bool;
+
+#[derive(Clone, Copy)]
+struct Handler {
+    function: IrqHandler,
+    context: usize,
+}
+
+lazy_static! {
+    /// Handlers of each vector, indexed from `FIRST_VECTOR`.
+    static ref HANDLERS: RwLock<Vec<Vec<Handler>>> = {
+        RwLock::new((0..VECTOR_COUNT).map(|_| Vec::new()).collect())
+    };
+
+    /// Bitmap of the allocated vectors, the vectors with a fixed use are always allocated.
+    static ref ALLOCATED: Mutex<[u64; 4]> = {
+        let mut allocated = [0; 4];
+        let fixed = (0..pic::VECTOR_BASE + pic::IRQ_COUNT)
+            .chain([TIMER_VECTOR, IPI_VECTOR, SPURIOUS_VECTOR].iter().cloned());
+        for vector in fixed {
+            allocated[vector as usize / 64] |= 1 << (vector % 64);
+        }
+        Mutex::new(allocated)
+    };
+
+    /// Vector assigned to each ISA IRQ by `register_isa_irq_handler`, zero when there is none.
+    static ref ISA_VECTORS: Mutex<[u8; 16]> = Mutex::new([0; 16]);
+}
+
+/// Allocate a free vector.
+///
+/// ## Returns
+/// `None` when all the vectors are in use.
+pub fn allocate_vector() -> Option<u8> {
+    let mut allocated = ALLOCATED.lock();
+
+    let vector = match (FIRST_VECTOR as usize..256)
+        .find(|&vector| allocated[vector / 64] & 1 << (vector % 64) == 0) {
+        Some(vector) => vector,
+        None => return None,
+    };
+
+    allocated[vector / 64] |= 1 << (vector % 64);
+    Some(vector as u8)
+}
+
+/// Free a vector allocated with `allocate_vector`, its handlers must have been unregistered.
+pub fn free_vector(vector: u8) {
+    assert!(HANDLERS.read()[(vector - FIRST_VECTOR) as usize].is_empty(),
+            "vector {:#x} still has handlers",
+            vector);
+
+    ALLOCATED.lock()[vector as usize / 64] &= !(1 << (vector % 64));
+}
+
+/// Attach a handler to a vector, the handlers already registered on the vector keep running.
+///
+/// ## Params
+/// * `vector` - vector of the interrupt, it must not be used by the CPU exceptions.
+/// * `handler` - function called on each interrupt.
+/// * `context` - value passed to the handler, usually the address of the device state.
+pub fn register_irq_handler(vector: u8, handler: IrqHandler, context: usize) {
+    assert!(vector >= FIRST_VECTOR, "vector {:#x} is used by the CPU exceptions", vector);
+
+    let handler = Handler {
+        function: handler,
+        context: context,
+    };
+    without_interrupts(|| HANDLERS.write()[(vector - FIRST_VECTOR) as usize].push(handler));
+}
+
+/// Detach a handler registered with `register_irq_handler`.
+///
+/// ## Returns
+/// `false` if the handler wasn't registered on the vector with the given context.
+pub fn unregister_irq_handler(vector: u8, handler: IrqHandler, context: usize) -> bool {
+    without_interrupts(|| {
+        let mut handlers = HANDLERS.write();
+        let handlers = &mut handlers[(vector - FIRST_VECTOR) as usize];
+
+        let position = handlers.iter().position(|registered| {
+            registered.function as usize == handler as usize && registered.context == context
+        });
+        match position {
+            Some(position) => {
+                handlers.remove(position);
+                true
+            }
+            None => false,
+        }
+    })
+}
+
+/// Attach a handler to an ISA IRQ and route the IRQ to the current CPU. With the I/O APIC the IRQ
+/// gets a vector the first time it is claimed, with the PICs the vector of the IRQ is fixed.
+///
+/// ## Returns
+/// The vector of the IRQ, or `None` when there are no free vectors.
+pub fn register_isa_irq_handler(irq: u8, handler: IrqHandler, context: usize) -> Option<u8> {
+    assert!(irq < pic::IRQ_COUNT, "invalid ISA IRQ {}", irq);
+
+    if pic::is_active() {
+        let vector = pic::VECTOR_BASE + irq;
+        register_irq_handler(vector, handler, context);
+        pic::unmask(irq);
+        return Some(vector);
+    }
+
+    let mut isa_vectors = ISA_VECTORS.lock();
+
+    // the IRQ is already routed, share its vector
+    if isa_vectors[irq as usize] != 0 {
+        register_irq_handler(isa_vectors[irq as usize], handler, context);
+        return Some(isa_vectors[irq as usize]);
+    }
+
+    let vector = match allocate_vector() {
+        Some(vector) => vector,
+        None => return None,
+    };
+    register_irq_handler(vector, handler, context);
+
+    let cpu = unsafe { LOCAL_APIC.id() } as u8;
+    if !ioapic::route_irq(ioapic::isa_irq_to_gsi(irq), vector, cpu) {
+        unregister_irq_handler(vector, handler, context);
+        free_vector(vector);
+        return None;
+    }
+
+    isa_vectors[irq as usize] = vector;
+    Some(vector)
+}
+
+/// Run the handlers of a vector and acknowledge the interrupt, it is called by
+/// `interrupt_dispatch`.
+pub fn irq_dispatch(vector: u8) {
+    // the spurious interrupts of the Local APIC don't set its In-Service Register
+    if vector == SPURIOUS_VECTOR {
+        return;
+    }
+
+    let pic_irq = if vector >= pic::VECTOR_BASE && vector < pic::VECTOR_BASE + pic::IRQ_COUNT {
+        Some(vector - pic::VECTOR_BASE)
+    } else {
+        None
+    };
+
+    // the PICs can raise spurious IRQs even when all of their IRQs are masked
+    if let Some(irq) = pic_irq {
+        if pic::is_spurious(irq) {
+            return;
+        }
+    }
+
+    let handled = HANDLERS.read()[(vector - FIRST_VECTOR) as usize]
+        .iter()
+        .fold(false, |handled, handler| (handler.function)(handler.context) | handled);
+    if !handled {
+        debugln!("unhandled interrupt on vector {:#x}", vector);
+    }
+
+    match pic_irq {
+        Some(irq) => pic::end_of_interrupt(irq),
+        None => unsafe { LOCAL_APIC.end_of_interrupt() },
+    }
+}
+
+/// Run `f` with the interrupts disabled, so the handlers of this CPU can't run while the handlers
+/// are being changed.
+fn without_interrupts<F, R>(f: F) -> R
+    where F: FnOnce() -> R
+{
+    /// Interrupt enable bit of RFLAGS
+    const RFLAGS_IF: u64 = 1 << 9;
+
+    let rflags: u64;
+    unsafe { asm!("pushfq; pop $0; cli" : "=r"(rflags) :: "memory" : "volatile") };
+
+    let result = f();
+
+    if rflags & RFLAGS_IF != 0 {
+        unsafe { asm!("sti" :::: "volatile") };
+    }
+    result
+}
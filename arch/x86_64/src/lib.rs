@@ -1,4 +1,3 @@
-#![feature(abi_x86_interrupt)]
 #![feature(asm)]
 #![feature(const_fn, unique)]
 #![feature(lang_items)]
@@ -40,6 +39,9 @@ pub mod memory;
 /// Interrupt instructions
 pub mod interrupts;
 
+/// Stack backtraces
+pub mod backtrace;
+
 /// Initialization and start function
 pub mod start;
 
@@ -56,6 +58,7 @@ extern "C" fn eh_personality() {}
 pub extern "C" fn panic_fmt(fmt: core::fmt::Arguments, file: &'static str, line: u32) -> ! {
     println!("\n\nPANIC in {} at line {}:", file, line);
     println!("    {}", fmt);
+    backtrace::print();
     loop {}
 }
 
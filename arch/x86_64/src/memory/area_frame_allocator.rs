@@ -7,98 +7,211 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-use memory::{Frame, FrameAllocator};
-use multiboot2::{MemoryAreaIter, MemoryArea};
+use memory::{PAGE_SIZE, Frame, FrameAllocator};
+use memory::paging::PhysicalAddress;
+use memory::reserved::ReservedRanges;
+use multiboot2::MemoryAreaIter;
 
-/// A frame allocator that uses the memory areas from the multiboot information structure as
-/// source. The {kernel, multiboot}_{start, end} fields are used to avoid returning memory that is
-/// already in use.
+/// Maximum amount of physical memory tracked by the frame allocator (16 GiB). Frames above this
+/// limit are ignored.
+const MAX_PHYSICAL_MEMORY: usize = 16 * 1024 * 1024 * 1024;
+
+/// Number of frames that can be tracked by the bitmap.
+const MAX_FRAMES: usize = MAX_PHYSICAL_MEMORY / PAGE_SIZE;
+
+/// Number of bits stored in each bitmap word.
+const BITS_PER_WORD: usize = 64;
+
+/// Bitmap with one bit per physical frame, a set bit means that the frame is in use.
 ///
-/// `kernel_end` and `multiboot_end` are _inclusive_ bounds.
+/// The bitmap lives on the kernel `.bss` section since it is needed before the heap is available.
+static mut FRAME_BITMAP: [u64; MAX_FRAMES / BITS_PER_WORD] = [0; MAX_FRAMES / BITS_PER_WORD];
+
+/// A frame allocator that keeps track of every physical frame using a bitmap. The bitmap is seeded
+/// from the memory areas of the multiboot information structure, the frames of the reserved ranges
+/// are marked as used.
 pub struct AreaFrameAllocator {
-    next_free_frame: Frame,
-    current_area: Option<&'static MemoryArea>,
-    areas: MemoryAreaIter,
-    kernel_start: Frame,
-    kernel_end: Frame,
-    multiboot_start: Frame,
-    multiboot_end: Frame,
+    bitmap: &'static mut [u64],
+    frame_count: usize,
+    next_free_frame: usize,
+    free_frames: usize,
+    usable_frames: usize,
 }
 
 impl AreaFrameAllocator {
-    pub fn new(kernel_start: usize,
-               kernel_end: usize,
-               multiboot_start: usize,
-               multiboot_end: usize,
-               memory_areas: MemoryAreaIter)
-               -> AreaFrameAllocator {
+    /// Create a new frame allocator from the multiboot memory areas. The frames that overlap any
+    /// of the reserved ranges are never handed out.
+    pub fn new(memory_areas: MemoryAreaIter, reserved: &ReservedRanges) -> AreaFrameAllocator {
+        // this is safe since `memory::init` ensures that only one allocator is ever created
+        let bitmap = unsafe { &mut FRAME_BITMAP[..] };
+
+        // start with every frame marked as used, only frames inside a memory area are usable
+        for word in bitmap.iter_mut() {
+            *word = !0;
+        }
+
         let mut allocator = AreaFrameAllocator {
-            next_free_frame: Frame::containing_address(0),
-            current_area: None,
-            areas: memory_areas,
-            kernel_start: Frame::containing_address(kernel_start),
-            kernel_end: Frame::containing_address(kernel_end),
-            multiboot_start: Frame::containing_address(multiboot_start),
-            multiboot_end: Frame::containing_address(multiboot_end),
+            bitmap: bitmap,
+            frame_count: 0,
+            next_free_frame: 0,
+            free_frames: 0,
+            usable_frames: 0,
         };
-        allocator.choose_next_area();
+
+        for area in memory_areas {
+            // only use the frames that are fully contained on the area
+            let start = Frame::containing_address(area.base_addr as usize + PAGE_SIZE - 1);
+            let end = Frame::containing_address((area.base_addr + area.length) as usize);
+
+            for number in start.number..end.number {
+                allocator.set_free(number);
+            }
+        }
+        allocator.usable_frames = allocator.free_frames;
+
+        // the frames partially covered by a reserved range can't be used either
+        for range in reserved.iter() {
+            allocator.reserve_range(Frame::containing_address(range.start),
+                                    Frame::containing_address(range.end - 1));
+        }
+
         allocator
     }
 
-    fn choose_next_area(&mut self) {
-        self.current_area = self.areas
-                                .clone()
-                                .filter(|area| {
-                                    let address = area.base_addr + area.length - 1;
-                                    Frame::containing_address(address as usize) >=
-                                    self.next_free_frame
-                                })
-                                .min_by_key(|area| area.base_addr);
-
-        if let Some(area) = self.current_area {
-            let start_frame = Frame::containing_address(area.base_addr as usize);
-            if self.next_free_frame < start_frame {
-                self.next_free_frame = start_frame;
+    /// Mark all the frames between `start` and `end` (inclusive) as used.
+    pub fn reserve_range(&mut self, start: Frame, end: Frame) {
+        for frame in Frame::range_inclusive(start, end) {
+            self.set_used(frame.number);
+        }
+    }
+
+    /// Make the frames fully contained on the physical range between `start` and `end` (exclusive)
+    /// available, it is used to hand back memory that was reserved at boot.
+    ///
+    /// ## Returns
+    /// The number of frames added to the allocator.
+    pub fn free_range(&mut self, start: PhysicalAddress, end: PhysicalAddress) -> usize {
+        let start = Frame::containing_address(start + PAGE_SIZE - 1);
+        let end = Frame::containing_address(end);
+        let free_frames = self.free_frames;
+
+        for number in start.number..end.number {
+            self.set_free(number);
+        }
+
+        let freed = self.free_frames - free_frames;
+        self.usable_frames += freed;
+        freed
+    }
+
+    /// Number of frames that can still be allocated.
+    pub fn free_frames(&self) -> usize {
+        self.free_frames
+    }
+
+    /// Number of frames inside of the usable memory areas.
+    pub fn usable_frames(&self) -> usize {
+        self.usable_frames
+    }
+
+    /// Check if the given frame number is in use.
+    fn is_used(&self, number: usize) -> bool {
+        self.bitmap[number / BITS_PER_WORD] & (1 << (number % BITS_PER_WORD)) != 0
+    }
+
+    /// Mark a frame as free.
+    fn set_free(&mut self, number: usize) {
+        if number >= MAX_FRAMES || !self.is_used(number) {
+            return;
+        }
+
+        self.bitmap[number / BITS_PER_WORD] &= !(1 << (number % BITS_PER_WORD));
+        self.free_frames += 1;
+
+        if number >= self.frame_count {
+            self.frame_count = number + 1;
+        }
+        if number < self.next_free_frame {
+            self.next_free_frame = number;
+        }
+    }
+
+    /// Mark a frame as used.
+    fn set_used(&mut self, number: usize) {
+        if number >= MAX_FRAMES || self.is_used(number) {
+            return;
+        }
+
+        self.bitmap[number / BITS_PER_WORD] |= 1 << (number % BITS_PER_WORD);
+        self.free_frames -= 1;
+    }
+
+    /// Find the first free frame starting at `start`.
+    fn find_free(&self, start: usize) -> Option<usize> {
+        let mut number = start;
+        while number < self.frame_count {
+            // skip fully used words
+            if number % BITS_PER_WORD == 0 && self.bitmap[number / BITS_PER_WORD] == !0 {
+                number += BITS_PER_WORD;
+                continue;
             }
+
+            if !self.is_used(number) {
+                return Some(number);
+            }
+            number += 1;
         }
+
+        None
     }
 }
 
 impl FrameAllocator for AreaFrameAllocator {
     fn allocate_frame(&mut self) -> Option<Frame> {
-        if let Some(area) = self.current_area {
-            // "clone" the frame to return it if it's free. Frame doesn't
-            // implement Clone, but we can construct an identical frame.
-            let frame = Frame { number: self.next_free_frame.number };
-
-            // the last frame of the current area
-            let current_area_last_frame = {
-                let address = area.base_addr + area.length - 1;
-                Frame::containing_address(address as usize)
-            };
-
-            if frame > current_area_last_frame {
-                // all frames of current area are used, switch to next area
-                self.choose_next_area();
-            } else if frame >= self.kernel_start && frame <= self.kernel_end {
-                // `frame` is used by the kernel
-                self.next_free_frame = Frame { number: self.kernel_end.number + 1 };
-            } else if frame >= self.multiboot_start && frame <= self.multiboot_end {
-                // `frame` is used by the multiboot information structure
-                self.next_free_frame = Frame { number: self.multiboot_end.number + 1 };
-            } else {
-                // frame is unused, increment `next_free_frame` and return it
-                self.next_free_frame.number += 1;
-                return Some(frame);
+        let start = self.next_free_frame;
+        match self.find_free(start) {
+            Some(number) => {
+                self.set_used(number);
+                self.next_free_frame = number + 1;
+                Some(Frame { number: number })
+            }
+            None => None, // no free frames left
+        }
+    }
+
+    fn allocate_frames(&mut self, count: usize) -> Option<Frame> {
+        if count == 0 {
+            return None;
+        }
+
+        // search for `count` consecutive free frames
+        let mut start = self.next_free_frame;
+        while let Some(first) = self.find_free(start) {
+            if first + count > self.frame_count {
+                return None;
+            }
+
+            match (first..first + count).find(|&number| self.is_used(number)) {
+                // the run was interrupted, continue after the used frame
+                Some(used) => start = used + 1,
+                None => {
+                    for number in first..first + count {
+                        self.set_used(number);
+                    }
+                    if first == self.next_free_frame {
+                        self.next_free_frame = first + count;
+                    }
+                    return Some(Frame { number: first });
+                }
             }
-            // `frame` was not valid, try it again with the updated `next_free_frame`
-            self.allocate_frame()
-        } else {
-            None // no free frames left
         }
+
+        None
     }
 
-    fn deallocate_frame(&mut self, _frame: Frame) {
-        unimplemented!()
+    fn deallocate_frame(&mut self, frame: Frame) {
+        assert!(frame.number < self.frame_count && self.is_used(frame.number),
+                "frame {:#x} is not allocated", frame.start_address());
+        self.set_free(frame.number);
     }
 }
@@ -0,0 +1,106 @@
+//! # Zeroed Frame Pool
+//!
+//! Frames mapped to user space must never carry data of their previous owner. The frames released
+//! by user mappings are zeroed as soon as they are freed and kept on a pool, the pool is topped up
+//! with zeroed frames when the CPU is idle, so most user allocations don't have to zero a frame on
+//! the spot.
+
+use core::ptr;
+use spin::Mutex;
+
+use memory::{PAGE_SIZE, Frame, FrameAllocator, GlobalFrameAllocator};
+use memory::stats::{self, FrameUse};
+
+/// Maximum number of frames kept on the pool (256 KiB).
+const POOL_SIZE: usize = 64;
+
+/// Stack of zeroed frames, stored by their number so the pool doesn't need the heap.
+struct Pool {
+    frames: [usize; POOL_SIZE],
+    len: usize,
+}
+
+impl Pool {
+    /// Add a frame to the pool.
+    ///
+    /// ## Returns
+    /// The frame back when the pool is full.
+    fn push(&mut self, frame: Frame) -> Result<(), Frame> {
+        if self.len == POOL_SIZE {
+            return Err(frame);
+        }
+
+        self.frames[self.len] = frame.number;
+        self.len += 1;
+        Ok(())
+    }
+
+    /// Take a frame from the pool.
+    fn pop(&mut self) -> Option<Frame> {
+        if self.len == 0 {
+            return None;
+        }
+
+        self.len -= 1;
+        Some(Frame { number: self.frames[self.len] })
+    }
+}
+
+static POOL: Mutex<Pool> = Mutex::new(Pool {
+    frames: [0; POOL_SIZE],
+    len: 0,
+});
+
+/// Fill a frame with zeros through the physical memory direct map.
+fn zero(frame: &Frame) {
+    unsafe { ptr::write_bytes(frame.virtual_address() as *mut u8, 0, PAGE_SIZE) };
+}
+
+/// Allocate a zeroed frame, it is taken from the pool when possible.
+///
+/// ## Returns
+/// `None` when there are no free frames left.
+pub fn allocate_zeroed() -> Option<Frame> {
+    if let Some(frame) = POOL.lock().pop() {
+        stats::frames_freed(FrameUse::Zeroed, 1);
+        return Some(frame);
+    }
+
+    GlobalFrameAllocator.allocate_frame().map(|frame| {
+        zero(&frame);
+        frame
+    })
+}
+
+/// Zero a frame that is no longer used and keep it on the pool. It is returned to the frame
+/// allocator when the pool is full.
+pub fn release(frame: Frame) {
+    zero(&frame);
+
+    match POOL.lock().push(frame) {
+        Ok(()) => stats::frames_allocated(FrameUse::Zeroed, 1),
+        Err(frame) => GlobalFrameAllocator.deallocate_frame(frame),
+    }
+}
+
+/// Top up the pool with zeroed frames. It is meant to be called when the CPU has nothing else to
+/// do, the pool lock isn't held while the frames are zeroed.
+pub fn refill() {
+    while POOL.lock().len < POOL_SIZE {
+        let frame = match GlobalFrameAllocator.allocate_frame() {
+            Some(frame) => frame,
+            None => return,
+        };
+
+        zero(&frame);
+
+        match POOL.lock().push(frame) {
+            Ok(()) => stats::frames_allocated(FrameUse::Zeroed, 1),
+            Err(frame) => {
+                // the pool was filled by `release` in the meantime
+                GlobalFrameAllocator.deallocate_frame(frame);
+                return;
+            }
+        }
+    }
+}
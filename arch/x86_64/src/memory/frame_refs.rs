@@ -0,0 +1,48 @@
+//! # Frame Reference Counting
+//!
+//! Frames mapped by more than one page, like copy-on-write pages or shared read-only segments,
+//! have their number of references tracked here. Frames without an entry have a single owner.
+
+use collections::btree_map::BTreeMap;
+use spin::Mutex;
+
+use memory::Frame;
+
+lazy_static! {
+    /// Number of references of each shared frame, indexed by the frame number.
+    static ref SHARED_FRAMES: Mutex<BTreeMap<usize, usize>> = Mutex::new(BTreeMap::new());
+}
+
+/// Add a new reference to the frame.
+pub fn acquire(frame: &Frame) {
+    let mut shared_frames = SHARED_FRAMES.lock();
+    *shared_frames.entry(frame.number).or_insert(1) += 1;
+}
+
+/// Remove a reference to the frame.
+///
+/// ## Returns
+/// `true` if it was the last reference, so the frame can be freed.
+pub fn release(frame: &Frame) -> bool {
+    let mut shared_frames = SHARED_FRAMES.lock();
+
+    let remaining = match shared_frames.get_mut(&frame.number) {
+        Some(count) => {
+            *count -= 1;
+            *count
+        }
+        None => return true,
+    };
+
+    // the frame has a single owner again
+    if remaining == 1 {
+        shared_frames.remove(&frame.number);
+    }
+
+    false
+}
+
+/// Number of references to the frame.
+pub fn count(frame: &Frame) -> usize {
+    SHARED_FRAMES.lock().get(&frame.number).cloned().unwrap_or(1)
+}
@@ -0,0 +1,225 @@
+//! # Kernel Address Space Layout
+//!
+//! The physical memory direct map, the heap, the stack area and the MMIO area are placed on
+//! randomly chosen P4 entries of the kernel half at boot, so their addresses can't be guessed. The
+//! randomness comes from RDSEED or RDRAND when they are available, otherwise the TSC jitter is mixed
+//! with the RTC time.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+use raw_cpuid::CpuId;
+
+use memory::{HEAP_MAX_SIZE, PHYSICAL_MEMORY_MAX_SIZE};
+use memory::paging::{VirtualAddress, KERNEL_P4_INDEX};
+
+/// First P4 entry that isn't randomized, the entries from here on hold the temporary page, the
+/// recursive mapping and the kernel image.
+const FIXED_P4_INDEX: usize = 509;
+
+/// Size of the virtual memory covered by a P4 entry (512 GiB).
+const P4_ENTRY_SIZE: usize = 512 * 1024 * 1024 * 1024;
+
+/// Number of retries for RDRAND and RDSEED before giving up on them.
+const HARDWARE_RETRIES: usize = 10;
+
+static PHYSICAL_MEMORY_OFFSET: AtomicUsize = AtomicUsize::new(0);
+static HEAP_START: AtomicUsize = AtomicUsize::new(0);
+static STACK_AREA_START: AtomicUsize = AtomicUsize::new(0);
+static MMIO_AREA_START: AtomicUsize = AtomicUsize::new(0);
+
+/// Virtual address where all the usable physical memory is mapped.
+pub fn physical_memory_offset() -> VirtualAddress {
+    PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed)
+}
+
+/// Start of the kernel heap.
+pub fn heap_start() -> VirtualAddress {
+    HEAP_START.load(Ordering::Relaxed)
+}
+
+/// Start of the virtual memory area used for kernel stacks.
+pub fn stack_area_start() -> VirtualAddress {
+    STACK_AREA_START.load(Ordering::Relaxed)
+}
+
+/// Start of the virtual memory area where device memory is mapped by `map_mmio`.
+pub fn mmio_area_start() -> VirtualAddress {
+    MMIO_AREA_START.load(Ordering::Relaxed)
+}
+
+/// Choose the randomized layout, it must be called before anything uses the areas.
+pub fn init() {
+    let mut random = Random::new();
+
+    // the direct map spans several consecutive entries
+    let direct_map_entries = PHYSICAL_MEMORY_MAX_SIZE / P4_ENTRY_SIZE;
+    let direct_map_slots = FIXED_P4_INDEX - KERNEL_P4_INDEX - direct_map_entries + 1;
+    let direct_map_index = KERNEL_P4_INDEX + random.below(direct_map_slots);
+
+    // every other area uses a whole entry that isn't used yet
+    let mut used = [direct_map_index, 0, 0, 0];
+    let mut pick_entry = |random: &mut Random, count: usize| loop {
+        let index = KERNEL_P4_INDEX + random.below(FIXED_P4_INDEX - KERNEL_P4_INDEX);
+        let taken = (index >= used[0] && index < used[0] + direct_map_entries) ||
+                    used[1..count].contains(&index);
+        if !taken {
+            used[count] = index;
+            return index;
+        }
+    };
+    let heap_index = pick_entry(&mut random, 1);
+    let stack_index = pick_entry(&mut random, 2);
+    let mmio_index = pick_entry(&mut random, 3);
+
+    // the heap is also placed on a random slot of its entry
+    let heap_offset = random.below(P4_ENTRY_SIZE / HEAP_MAX_SIZE) * HEAP_MAX_SIZE;
+
+    PHYSICAL_MEMORY_OFFSET.store(p4_entry_address(direct_map_index), Ordering::Relaxed);
+    HEAP_START.store(p4_entry_address(heap_index) + heap_offset, Ordering::Relaxed);
+    STACK_AREA_START.store(p4_entry_address(stack_index), Ordering::Relaxed);
+    MMIO_AREA_START.store(p4_entry_address(mmio_index), Ordering::Relaxed);
+
+    // the addresses must stay secret for KASLR to work, they are only logged on request
+    if cfg!(feature = "print_layout") {
+        debugln!("layout: direct map {:#x}, heap {:#x}, stacks {:#x}, mmio {:#x}",
+                 physical_memory_offset(),
+                 heap_start(),
+                 stack_area_start(),
+                 mmio_area_start());
+    }
+}
+
+/// Canonical address of the first byte covered by the given P4 entry of the kernel half.
+fn p4_entry_address(index: usize) -> VirtualAddress {
+    0xffff_0000_0000_0000 | index * P4_ENTRY_SIZE
+}
+
+/// Source of the random numbers used to choose the layout.
+struct Random {
+    rdseed: bool,
+    rdrand: bool,
+    /// State of the software generator, used when there is no hardware generator
+    state: u64,
+}
+
+impl Random {
+    fn new() -> Random {
+        let cpuid = CpuId::new();
+        let rdrand = cpuid.get_feature_info().map_or(false, |info| info.has_rdrand());
+        // RDSEED is reported on the extended features leaf
+        let rdseed = cpuid.get_extended_feature_info().is_some() &&
+                     cpuid_leaf7_ebx() & 1 << 18 != 0;
+
+        let mut random = Random {
+            rdseed: rdseed,
+            rdrand: rdrand,
+            state: 0,
+        };
+
+        if !rdseed && !rdrand {
+            random.state = software_seed();
+        }
+
+        random
+    }
+
+    /// Get a random number.
+    fn next(&mut self) -> u64 {
+        if self.rdseed {
+            if let Some(value) = retry(rdseed) {
+                return value;
+            }
+        }
+        if self.rdrand {
+            if let Some(value) = retry(rdrand) {
+                return value;
+            }
+        }
+
+        // the hardware generators failed, fall back to the software generator
+        if self.state == 0 {
+            self.state = software_seed();
+        }
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        mix(self.state)
+    }
+
+    /// Get a random number lower than `bound`.
+    fn below(&mut self, bound: usize) -> usize {
+        (self.next() % bound as u64) as usize
+    }
+}
+
+/// Call `generator` until it succeeds or runs out of retries.
+fn retry(generator: fn() -> Option<u64>) -> Option<u64> {
+    (0..HARDWARE_RETRIES).filter_map(|_| generator()).next()
+}
+
+/// Get a random number with RDSEED, it fails when the entropy source is exhausted.
+fn rdseed() -> Option<u64> {
+    let value: u64;
+    let success: u8;
+    unsafe {
+        asm!("rdseed $0; setc $1" : "=r"(value), "=r"(success) ::: "volatile");
+    }
+    if success != 0 { Some(value) } else { None }
+}
+
+/// Get a random number with RDRAND.
+fn rdrand() -> Option<u64> {
+    let value: u64;
+    let success: u8;
+    unsafe {
+        asm!("rdrand $0; setc $1" : "=r"(value), "=r"(success) ::: "volatile");
+    }
+    if success != 0 { Some(value) } else { None }
+}
+
+/// Register EBX of the CPUID extended features leaf, `raw_cpuid` doesn't expose RDSEED.
+fn cpuid_leaf7_ebx() -> u32 {
+    let ebx: u32;
+    unsafe {
+        asm!("cpuid"
+             : "={ebx}"(ebx)
+             : "{eax}"(7), "{ecx}"(0)
+             : "eax", "ecx", "edx");
+    }
+    ebx
+}
+
+/// Read the time stamp counter.
+fn rdtsc() -> u64 {
+    let low: u32;
+    let high: u32;
+    unsafe {
+        asm!("rdtsc" : "={eax}"(low), "={edx}"(high) ::: "volatile");
+    }
+    (high as u64) << 32 | low as u64
+}
+
+/// Build a seed from the RTC time and the jitter of the TSC while reading the RTC ports.
+fn software_seed() -> u64 {
+    use device::rtc::Rtc;
+    use x86_64::instructions::port::{inb, outb};
+
+    let mut seed = mix(Rtc::new().time() ^ rdtsc());
+
+    for _ in 0..64 {
+        let start = rdtsc();
+        // the time taken by port I/O varies, read the RTC seconds register
+        let second = unsafe {
+            outb(0x70, 0);
+            inb(0x71)
+        };
+        seed = mix(seed ^ rdtsc().wrapping_sub(start) ^ second as u64);
+    }
+
+    seed
+}
+
+/// Scramble the bits of a value (the SplitMix64 finalizer).
+fn mix(value: u64) -> u64 {
+    let mut value = value;
+    value = (value ^ (value >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    value = (value ^ (value >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    value ^ (value >> 31)
+}
@@ -0,0 +1,174 @@
+//! # Memory Mapped I/O
+//!
+//! Device memory is mapped on its own virtual memory area, with the caching type chosen by the
+//! driver. The PAT is programmed so that each caching type can be selected only with the
+//! `WRITE_THROUGH` and `NO_CACHE` flags of the page table entries.
+
+use raw_cpuid::CpuId;
+use spin::Mutex;
+
+use memory::{PAGE_SIZE, MMIO_AREA_SIZE, Frame, GlobalFrameAllocator, mmio_area_start};
+use memory::paging::{self, Mapper, Page, PhysicalAddress, VirtualAddress, EntryFlags};
+
+/// Page Attribute Table MSR
+const IA32_PAT: u32 = 0x277;
+
+/// CR0 bits that disable the caches and the write-through of the caches
+const CR0_CD: u64 = 1 << 30;
+const CR0_NW: u64 = 1 << 29;
+/// CR4 bit that enables the global pages, changing it flushes the whole TLB
+const CR4_PGE: u64 = 1 << 7;
+/// Interrupt enable bit of RFLAGS
+const RFLAGS_IF: u64 = 1 << 9;
+
+/// Memory types of the PAT entries, indexed by `PCD << 1 | PWT`. The entries selected with the PAT
+/// bit are programmed the same way, since the bit is never used.
+const PAT_ENTRIES: [u64; 4] = [
+    6, // write back
+    4, // write through
+    1, // write combining
+    0, // uncacheable
+];
+
+/// Caching type of a memory mapping.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CacheType {
+    /// Uncacheable, for device registers
+    Uncacheable,
+    /// Write combining, for framebuffers
+    WriteCombining,
+    /// Write through
+    WriteThrough,
+    /// Write back, the caching type of the normal memory
+    WriteBack,
+}
+
+impl CacheType {
+    /// Page table flags that select the caching type with the PAT programmed by `init`.
+    fn flags(&self) -> EntryFlags {
+        match *self {
+            CacheType::WriteBack => EntryFlags::empty(),
+            CacheType::WriteThrough => paging::WRITE_THROUGH,
+            CacheType::WriteCombining => paging::NO_CACHE,
+            CacheType::Uncacheable => paging::NO_CACHE | paging::WRITE_THROUGH,
+        }
+    }
+}
+
+/// Offset of the next free page from the start of the MMIO area.
+static NEXT_OFFSET: Mutex<usize> = Mutex::new(0);
+
+/// Program the PAT of the current CPU. Every CPU must program its own PAT before it uses the
+/// MMIO mappings, otherwise it reads the caching type flags with the power-on PAT.
+///
+/// The first four entries of the default PAT are WB, WT, UC- and UC, so without PAT support write
+/// combining mappings fall back to UC-.
+pub fn init() {
+    if !CpuId::new().get_feature_info().map_or(false, |info| info.has_pat()) {
+        println!("PAT not supported, write combining is not available");
+        return;
+    }
+
+    let pat = PAT_ENTRIES.iter()
+        .chain(PAT_ENTRIES.iter())
+        .enumerate()
+        .fold(0, |pat, (index, &memory_type)| pat | memory_type << (index * 8));
+
+    unsafe { write_pat(pat) };
+}
+
+/// Change the PAT with the sequence of the Intel SDM (11.12.4 and 11.11.8). The caches and the TLB
+/// are flushed with the caches disabled before and after the write, so no cache line or TLB entry
+/// keeps the old memory type.
+unsafe fn write_pat(pat: u64) {
+    use x86_64::registers::msr::wrmsr;
+
+    let (rflags, cr0, cr4): (u64, u64, u64);
+    asm!("pushfq; pop $0; cli" : "=r"(rflags) :: "memory" : "volatile");
+    asm!("mov %cr0, $0" : "=r"(cr0));
+    asm!("mov %cr4, $0" : "=r"(cr4));
+
+    // enter the no-fill cache mode
+    asm!("mov $0, %cr0" :: "r"((cr0 | CR0_CD) & !CR0_NW) : "memory" : "volatile");
+    flush_caches_and_tlb(cr4);
+
+    wrmsr(IA32_PAT, pat);
+
+    flush_caches_and_tlb(cr4);
+    asm!("mov $0, %cr0" :: "r"(cr0) : "memory" : "volatile");
+
+    if rflags & RFLAGS_IF != 0 {
+        asm!("sti" :::: "volatile");
+    }
+}
+
+/// Write back and invalidate the caches, then flush the whole TLB, the global entries and all the
+/// PCIDs included, by toggling CR4.PGE.
+unsafe fn flush_caches_and_tlb(cr4: u64) {
+    asm!("wbinvd" ::: "memory" : "volatile");
+    asm!("mov $0, %cr4" :: "r"(cr4 ^ CR4_PGE) : "memory" : "volatile");
+    asm!("mov $0, %cr4" :: "r"(cr4) : "memory" : "volatile");
+}
+
+/// Map `size` bytes of device memory starting at the physical address `address`.
+///
+/// ## Params
+/// * `address` - physical address of the device memory, it doesn't need to be page aligned.
+/// * `size` - number of bytes to map.
+/// * `cache_type` - caching type of the mapping.
+///
+/// ## Returns
+/// The virtual address of `address`, or `None` if `size` is zero or the MMIO area is full.
+pub fn map_mmio(address: PhysicalAddress,
+                size: usize,
+                cache_type: CacheType)
+                -> Option<VirtualAddress> {
+    if size == 0 {
+        return None;
+    }
+
+    let start_frame = Frame::containing_address(address);
+    let end_frame = Frame::containing_address(address + size - 1);
+    let page_count = end_frame.number - start_frame.number + 1;
+
+    // reserve the virtual memory window
+    let start = {
+        let mut next_offset = NEXT_OFFSET.lock();
+        let offset = *next_offset;
+        if offset + page_count * PAGE_SIZE > MMIO_AREA_SIZE {
+            return None;
+        }
+        *next_offset += page_count * PAGE_SIZE;
+        mmio_area_start() + offset
+    };
+
+    // the MMIO area lives on the kernel half, so the pages are visible on every address space
+    let mut mapper = unsafe { Mapper::new() };
+    let mut frame_allocator = GlobalFrameAllocator;
+    let flags = paging::WRITABLE | paging::NO_EXECUTE | cache_type.flags();
+
+    let start_page = Page::containing_address(start);
+    for (page, frame) in Page::range_inclusive(start_page, start_page + (page_count - 1))
+        .zip(Frame::range_inclusive(start_frame, end_frame)) {
+        mapper.map_to(page, frame, flags, &mut frame_allocator);
+    }
+
+    Some(start + address % PAGE_SIZE)
+}
+
+/// Unmap device memory mapped with `map_mmio`. The frames aren't freed, they belong to the device
+/// or the firmware, and the virtual memory window isn't reused.
+///
+/// ## Params
+/// * `address` - virtual address returned by `map_mmio`.
+/// * `size` - number of bytes given to `map_mmio`.
+pub fn unmap_mmio(address: VirtualAddress, size: usize) {
+    let mut mapper = unsafe { Mapper::new() };
+    let mut frame_allocator = GlobalFrameAllocator;
+
+    let start_page = Page::containing_address(address);
+    let end_page = Page::containing_address(address + size - 1);
+    for page in Page::range_inclusive(start_page, end_page) {
+        mapper.unmap_return(page, &mut frame_allocator);
+    }
+}
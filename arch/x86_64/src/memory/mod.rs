@@ -1,18 +1,94 @@
 pub use self::area_frame_allocator::AreaFrameAllocator;
-pub use self::paging::ActivePageTable;
+pub use self::layout::{physical_memory_offset, heap_start, stack_area_start, mmio_area_start};
+pub use self::mmio::{CacheType, map_mmio, unmap_mmio};
+pub use self::paging::{ActivePageTable, AddressSpace, Region, RegionError, handle_page_fault};
 pub use self::paging::remap_the_kernel;
-pub use self::stack_allocator::Stack;
+pub use self::stack_allocator::{Stack, StackInfo, guard_page_owner};
+pub use self::stats::{MemoryStats, stats};
 
-use self::paging::PhysicalAddress;
+use self::paging::{PhysicalAddress, VirtualAddress};
+use core::sync::atomic::{AtomicBool, Ordering};
 use multiboot2::BootInformation;
+use spin::Mutex;
 
 mod area_frame_allocator;
+pub mod frame_pool;
+pub mod frame_refs;
+mod layout;
+mod mmio;
 pub mod paging;
+pub mod reserved;
+pub mod user_access;
 mod stack_allocator;
+mod stats;
 
 /// Size of a page
 pub const PAGE_SIZE: usize = 4096;
 
+/// Maximum size of the physical memory direct map (64 TiB).
+pub const PHYSICAL_MEMORY_MAX_SIZE: usize = 0x4000_0000_0000;
+
+/// Maximum size the kernel heap can grow to (1 GiB).
+pub const HEAP_MAX_SIZE: usize = 1024 * 1024 * 1024;
+
+/// Size of the virtual memory area used for kernel stacks (one P4 entry, 512 GiB). It is kept apart
+/// from the heap so the heap can grow.
+pub const STACK_AREA_SIZE: usize = 512 * 1024 * 1024 * 1024;
+
+/// Size of the virtual memory area used for device memory (one P4 entry, 512 GiB).
+pub const MMIO_AREA_SIZE: usize = 512 * 1024 * 1024 * 1024;
+
+/// Byte written over the freed frames when poisoning is enabled. Pointers read from freed memory
+/// are non-canonical, so using them faults right away.
+const POISON_BYTE: u8 = 0xde;
+
+/// Virtual address where the kernel is linked, see `linker.ld`. The kernel image, the VGA buffer
+/// and the multiboot information structure are mapped at this offset of their physical address.
+pub const KERNEL_OFFSET: usize = 0xffff_ffff_8000_0000;
+
+/// Convert a physical address into the virtual address where it can be accessed through the
+/// physical memory direct map.
+pub fn phys_to_virt(address: PhysicalAddress) -> VirtualAddress {
+    address + physical_memory_offset()
+}
+
+/// Convert a virtual address inside of the physical memory direct map into the physical address
+/// it maps.
+///
+/// ## Returns
+/// `None` if the address isn't part of the direct map.
+pub fn virt_to_phys(address: VirtualAddress) -> Option<PhysicalAddress> {
+    let offset = physical_memory_offset();
+    if address >= offset && address < offset + PHYSICAL_MEMORY_MAX_SIZE {
+        Some(address - offset)
+    } else {
+        None
+    }
+}
+
+/// Convert an address of the kernel image into its physical address. The boot code is linked at
+/// its physical address, so addresses below `KERNEL_OFFSET` are returned unchanged.
+pub fn kernel_virt_to_phys(address: VirtualAddress) -> PhysicalAddress {
+    if address >= KERNEL_OFFSET {
+        address - KERNEL_OFFSET
+    } else {
+        address
+    }
+}
+
+/// Frame allocator shared by the whole kernel, it is set up by `memory::init`.
+static FRAME_ALLOCATOR: Mutex<Option<AreaFrameAllocator>> = Mutex::new(None);
+
+/// Set when the freed frames are filled with `POISON_BYTE`.
+static POISON: AtomicBool = AtomicBool::new(false);
+
+/// Fill the freed frames and heap blocks with a poison pattern, so use-after-free bugs show up
+/// as faults instead of silently reading stale data. It is enabled by default on debug builds.
+pub fn set_poison(enabled: bool) {
+    POISON.store(enabled, Ordering::SeqCst);
+    ::hole_list_allocator::set_poison(enabled);
+}
+
 /// Initialize the memory system
 ///
 /// ## Returns
@@ -22,43 +98,88 @@ pub fn init(boot_info: &BootInformation) -> MemoryController {
     // insure that this function is only called once
     assert_has_not_been_called!("memory::init must be called only once");
 
+    // choose where the memory areas of the kernel half are placed
+    layout::init();
+
     // get the bootloader memory tag
     let memory_map_tag = boot_info.memory_map_tag().expect("Memory map tag required");
 
     // get the elf sections bootloader tag
     let elf_sections_tag = boot_info.elf_sections_tag().expect("Elf sections tag required");
 
-    // get the kernel start address
-    let kernel_start = elf_sections_tag.sections().map(|s| s.addr).min().unwrap();
+    // get the kernel start physical address
+    let kernel_start = elf_sections_tag.sections()
+        .filter(|s| s.is_allocated())
+        .map(|s| kernel_virt_to_phys(s.addr as usize))
+        .min()
+        .unwrap();
+
+    // get the kernel end physical address
+    let kernel_end = elf_sections_tag.sections()
+        .filter(|s| s.is_allocated())
+        .map(|s| kernel_virt_to_phys((s.addr + s.size) as usize))
+        .max()
+        .unwrap();
+
+    // the memory map only lists the usable areas, the total covers the holes between them
+    let total_memory = memory_map_tag.memory_areas()
+        .map(|area| (area.base_addr + area.length) as usize)
+        .max()
+        .unwrap_or(0);
+    let usable_memory = memory_map_tag.memory_areas()
+        .map(|area| area.length as usize)
+        .sum::<usize>();
+    let kernel_frames = (kernel_end - 1) / PAGE_SIZE - kernel_start / PAGE_SIZE + 1;
+    stats::init(total_memory, usable_memory, kernel_frames);
+
+    // make all the caching types available to the MMIO mappings
+    mmio::init();
+
+    // catch use-after-free bugs on debug builds
+    set_poison(cfg!(debug_assertions));
 
-    // get the kernel end address
-    let kernel_end = elf_sections_tag.sections().map(|s| s.addr + s.size).max().unwrap();
+    // collect the physical memory that must not be handed out
+    reserved::init(boot_info, kernel_start, kernel_end);
 
     // initialize the frame allocator
-    let mut frame_allocator = AreaFrameAllocator::new(kernel_start as usize,
-                                                      kernel_end as usize,
-                                                      boot_info.start_address(),
-                                                      boot_info.end_address(),
-                                                      memory_map_tag.memory_areas());
+    *FRAME_ALLOCATOR.lock() = Some(AreaFrameAllocator::new(memory_map_tag.memory_areas(),
+                                                           &reserved::ranges()));
+    let mut frame_allocator = GlobalFrameAllocator;
+
     // remap the kernel
     let mut active_table = remap_the_kernel(&mut frame_allocator, boot_info);
 
+    // tag the TLB entries with the address space they belong to
+    paging::pcid::init();
+
+    // the kernel half of the P4 table is shared by all the address spaces, so it must not change
+    active_table.create_kernel_tables(&mut frame_allocator);
+
     // remap heap
     use self::paging::Page;
-    use hole_list_allocator::{HEAP_START, HEAP_SIZE};
+    use hole_list_allocator::HEAP_SIZE;
 
-    let heap_start_page = Page::containing_address(HEAP_START);
-    let heap_end_page = Page::containing_address(HEAP_START + HEAP_SIZE - 1);
+    let heap_start_page = Page::containing_address(heap_start());
+    let heap_end_page = Page::containing_address(heap_start() + HEAP_SIZE - 1);
 
     for page in Page::range_inclusive(heap_start_page, heap_end_page) {
-        active_table.map(page, paging::WRITABLE, &mut frame_allocator);
+        active_table.map(page, paging::WRITABLE | paging::NO_EXECUTE, &mut frame_allocator);
     }
 
+    // nothing can be allocated before the heap start is set
+    ::hole_list_allocator::set_heap_start(heap_start());
+
+    // the stack registry needs the heap
+    paging::register_boot_stack();
+
+    // allow the heap to grow on demand
+    ::hole_list_allocator::set_grow_handler(grow_heap, HEAP_MAX_SIZE);
+
     // remap Stack
     let stack_allocator = {
         // calculate the start and end address of the stack
-        let stack_alloc_start = heap_end_page + 1;
-        let stack_alloc_end = stack_alloc_start + 100;
+        let stack_alloc_start = Page::containing_address(stack_area_start());
+        let stack_alloc_end = Page::containing_address(stack_area_start() + STACK_AREA_SIZE - 1);
 
         // create a new page range with the stack start address and end address
         let stack_alloc_range = Page::range_inclusive(stack_alloc_start, stack_alloc_end);
@@ -74,6 +195,60 @@ pub fn init(boot_info: &BootInformation) -> MemoryController {
     }
 }
 
+/// Set up the memory state of an application processor, it must be called by each AP before it
+/// uses any MMIO mapping.
+pub fn init_ap() {
+    // the PAT is per CPU, the APs start with the power-on PAT
+    mmio::init();
+}
+
+/// Give the ACPI reclaimable memory back to the frame allocator, it must only be called once the
+/// ACPI tables have been parsed and nothing references them anymore.
+pub fn reclaim_acpi_memory() {
+    use self::reserved::ReservedKind;
+
+    let mut frames = 0;
+    for range in reserved::remove(ReservedKind::AcpiReclaimable).iter() {
+        frames += FRAME_ALLOCATOR.lock()
+            .as_mut()
+            .expect("frame allocator not initialized")
+            .free_range(range.start, range.end);
+    }
+
+    stats::memory_reclaimed(frames * PAGE_SIZE);
+    println!("reclaimed {} KiB of ACPI memory", frames * PAGE_SIZE / 1024);
+}
+
+/// Map `size` bytes at the end of the kernel heap, it is called by the heap allocator when it needs
+/// to grow.
+///
+/// ## Returns
+/// The number of bytes mapped from `start`. When the frames run out the pages mapped so far are
+/// kept, the other CPUs may already cache them, so they can't be unmapped while the heap lock is
+/// held and the allocator adds them to the heap instead.
+fn grow_heap(start: VirtualAddress, size: usize) -> usize {
+    use self::paging::{Mapper, Page};
+
+    // the heap lives on the kernel half, so the new pages are visible on every address space
+    let mut mapper = unsafe { Mapper::new() };
+    let mut frame_allocator = GlobalFrameAllocator;
+
+    let start_page = Page::containing_address(start);
+    let end_page = Page::containing_address(start + size - 1);
+
+    let mut mapped = 0;
+    for page in Page::range_inclusive(start_page, end_page) {
+        let frame = match frame_allocator.allocate_frame() {
+            Some(frame) => frame,
+            None => break,
+        };
+        mapper.map_to(page, frame, paging::WRITABLE | paging::NO_EXECUTE, &mut frame_allocator);
+        mapped += PAGE_SIZE;
+    }
+
+    mapped
+}
+
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Frame {
     number: usize,
@@ -88,6 +263,11 @@ impl Frame {
         self.number * PAGE_SIZE
     }
 
+    /// Virtual address where the frame can be accessed through the physical memory direct map.
+    pub fn virtual_address(&self) -> VirtualAddress {
+        phys_to_virt(self.start_address())
+    }
+
     fn range_inclusive(start: Frame, end: Frame) -> FrameIter {
         FrameIter {
             start: start,
@@ -122,11 +302,62 @@ impl Iterator for FrameIter {
 pub trait FrameAllocator {
     fn allocate_frame(&mut self) -> Option<Frame>;
     fn deallocate_frame(&mut self, frame: Frame);
+
+    /// Allocate `count` physically contiguous frames.
+    ///
+    /// ## Returns
+    /// The first frame of the allocated range.
+    fn allocate_frames(&mut self, count: usize) -> Option<Frame> {
+        if count == 1 {
+            self.allocate_frame()
+        } else {
+            None
+        }
+    }
+
+    /// Deallocate `count` physically contiguous frames starting at `frame`.
+    fn deallocate_frames(&mut self, frame: Frame, count: usize) {
+        for number in frame.number..frame.number + count {
+            self.deallocate_frame(Frame { number: number });
+        }
+    }
+}
+
+/// Handle to the kernel frame allocator. It can be used wherever a `FrameAllocator` is needed, even
+/// when there is no access to the `MemoryController`.
+pub struct GlobalFrameAllocator;
+
+impl FrameAllocator for GlobalFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<Frame> {
+        FRAME_ALLOCATOR.lock()
+            .as_mut()
+            .expect("frame allocator not initialized")
+            .allocate_frame()
+    }
+
+    fn deallocate_frame(&mut self, frame: Frame) {
+        if POISON.load(Ordering::Relaxed) {
+            let address = frame.virtual_address() as *mut u8;
+            unsafe { ::core::ptr::write_bytes(address, POISON_BYTE, PAGE_SIZE) };
+        }
+
+        FRAME_ALLOCATOR.lock()
+            .as_mut()
+            .expect("frame allocator not initialized")
+            .deallocate_frame(frame)
+    }
+
+    fn allocate_frames(&mut self, count: usize) -> Option<Frame> {
+        FRAME_ALLOCATOR.lock()
+            .as_mut()
+            .expect("frame allocator not initialized")
+            .allocate_frames(count)
+    }
 }
 
 pub struct MemoryController {
     pub active_table: paging::ActivePageTable,
-    frame_allocator: AreaFrameAllocator,
+    frame_allocator: GlobalFrameAllocator,
     stack_allocator: stack_allocator::StackAllocator,
 }
 
@@ -135,16 +366,30 @@ impl MemoryController {
     ///
     /// ## Params
     /// * `size_in_pages` - number of pages to alloc.
+    /// * `name` - name used to identify the stack when it overflows.
     ///
     /// ## Returns
     /// Stack instance.
-    pub fn alloc_stack(&mut self, size_in_pages: usize) -> Option<Stack> {
+    pub fn alloc_stack(&mut self, size_in_pages: usize, name: &'static str) -> Option<Stack> {
+        let &mut MemoryController {
+            ref mut active_table,
+            ref mut frame_allocator,
+            ref mut stack_allocator } = self;
+
+        stack_allocator.alloc_stack(active_table, frame_allocator, size_in_pages, name)
+    }
+
+    /// Free a stack allocated with `alloc_stack`.
+    ///
+    /// ## Params
+    /// * `stack` - stack to free, it must not be in use.
+    pub fn free_stack(&mut self, stack: Stack) {
         let &mut MemoryController {
             ref mut active_table,
             ref mut frame_allocator,
             ref mut stack_allocator } = self;
 
-        stack_allocator.alloc_stack(active_table, frame_allocator, size_in_pages)
+        stack_allocator.free_stack(active_table, frame_allocator, stack)
     }
 
     /// Maps the page to the frame with the provided flags.
@@ -154,8 +399,8 @@ impl MemoryController {
         self.active_table.map_to(page, frame, flags, &mut self.frame_allocator);
     }
 
-    /// Flush the TLB table
+    /// Flush the TLB entries of every address space
     pub fn flush_all(&mut self) {
-        self.active_table.flush_all();
+        paging::pcid::flush_all_contexts();
     }
 }
@@ -0,0 +1,743 @@
+//! # Address Spaces
+//!
+//! An address space owns a P4 table with its own lower (user) half, the kernel half is shared by
+//! every address space.
+
+use collections::vec::Vec;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use x86_64::structures::idt::PageFaultErrorCode;
+
+use memory::{PAGE_SIZE, Frame, FrameAllocator, GlobalFrameAllocator};
+use memory::{frame_pool, frame_refs};
+use memory::stats::{self, FrameUse};
+use super::{Page, PageIter, VirtualAddress, ENTRY_COUNT, KERNEL_P4_INDEX, RECURSIVE_INDEX};
+use super::entry::*;
+use super::table::{Table, Level4};
+use super::{pcid, shootdown};
+
+/// End (exclusive) of the user half of the address space.
+pub const USER_END: VirtualAddress = 0x0000_8000_0000_0000;
+
+lazy_static! {
+    /// Address of the `AddressSpace` loaded by `AddressSpace::activate` on each CPU, indexed by the
+    /// CPU index, zero when there is none.
+    static ref CURRENT: Vec<AtomicUsize> = (0..shootdown::MAX_CPUS)
+        .map(|_| AtomicUsize::new(0))
+        .collect();
+}
+
+/// The address space loaded on the current CPU.
+///
+/// ## Returns
+/// `None` when the active page table isn't the one of an `AddressSpace`.
+fn current() -> Option<*mut AddressSpace> {
+    let current = CURRENT[shootdown::current_cpu()].load(Ordering::SeqCst) as *mut AddressSpace;
+    if current.is_null() || unsafe { !(*current).is_active() } {
+        None
+    } else {
+        Some(current)
+    }
+}
+
+/// CPUs whose TLB can hold the user half of the active page table, with the PCID of its entries.
+///
+/// ## Returns
+/// `None` when the active page table isn't the one of an `AddressSpace`, its user half was only
+/// used by the current CPU.
+pub fn active_user_cpus() -> Option<(usize, Option<u16>)> {
+    current().map(|current| {
+        let address_space = unsafe { &*current };
+        (address_space.cpus.load(Ordering::SeqCst), address_space.pcid)
+    })
+}
+
+/// Try to resolve a page fault on the user half of the current address space.
+///
+/// ## Returns
+/// `true` if the fault was resolved and the faulting code can be resumed.
+pub fn handle_page_fault(address: VirtualAddress, error_code: PageFaultErrorCode) -> bool {
+    if address >= USER_END {
+        return false;
+    }
+
+    let current = match current() {
+        Some(current) => current,
+        None => return false,
+    };
+
+    // the other CPUs running the address space can fault on it at the same time
+    let address_space = unsafe { &mut *current };
+    address_space.locked(|address_space| address_space.handle_page_fault(address, error_code))
+}
+
+/// Check that the `size` bytes starting at `start` are part of the regions of the active address
+/// space, and that the regions are writable when `write` is set.
+pub fn check_user_range(start: VirtualAddress,
+                        size: usize,
+                        write: bool)
+                        -> Result<(), RegionError> {
+    if start >= USER_END || USER_END - start < size {
+        return Err(RegionError::NotUserAddress);
+    }
+
+    let current = match current() {
+        Some(current) => current,
+        None => return Err(RegionError::NotMapped),
+    };
+
+    let address_space = unsafe { &mut *current };
+    address_space.locked(|address_space| {
+        // the range can be spread over adjacent regions
+        let end = start + size;
+        let mut address = start;
+        while address < end {
+            let region = match address_space.find_region(address) {
+                Some(region) => region,
+                None => return Err(RegionError::NotMapped),
+            };
+
+            if write && !region.flags.contains(WRITABLE) {
+                return Err(RegionError::AccessDenied);
+            }
+
+            address = region.end();
+        }
+
+        Ok(())
+    })
+}
+
+/// Errors returned when managing the regions of an address space.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionError {
+    /// The region is empty or isn't page aligned
+    InvalidRange,
+    /// The region isn't inside of the user half
+    NotUserAddress,
+    /// The region overlaps an existing region
+    Overlap,
+    /// There is no region starting at the given address
+    NotMapped,
+    /// There are no free frames left
+    OutOfMemory,
+    /// The access isn't allowed by the region flags
+    AccessDenied,
+}
+
+/// A range of virtual memory mapped with the same permissions.
+#[derive(Debug, Clone, Copy)]
+pub struct Region {
+    start: VirtualAddress,
+    size: usize,
+    flags: EntryFlags,
+}
+
+impl Region {
+    /// Start address of the region.
+    pub fn start(&self) -> VirtualAddress {
+        self.start
+    }
+
+    /// End address of the region (exclusive).
+    pub fn end(&self) -> VirtualAddress {
+        self.start + self.size
+    }
+
+    /// Size of the region in bytes.
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Flags used to map the pages of the region.
+    pub fn flags(&self) -> EntryFlags {
+        self.flags
+    }
+
+    /// Check if the address is part of the region.
+    pub fn contains(&self, address: VirtualAddress) -> bool {
+        address >= self.start && address < self.end()
+    }
+
+    /// Iterate all the pages of the region.
+    fn pages(&self) -> PageIter {
+        Page::range_inclusive(Page::containing_address(self.start),
+                              Page::containing_address(self.end() - 1))
+    }
+}
+
+/// Virtual address space of a user process.
+pub struct AddressSpace {
+    p4_frame: Frame,
+    /// Mapped regions sorted by the start address
+    regions: Vec<Region>,
+    /// PCID tagging the TLB entries of the address space, `None` when it doesn't have its own
+    pcid: Option<u16>,
+    /// Mask of the CPUs that activated the address space, their TLB can hold its entries
+    cpus: AtomicUsize,
+    /// Serializes the changes to the regions and the page tables, the address space can be used by
+    /// several CPUs at once
+    lock: AtomicBool,
+}
+
+/// Frames released while changing the mappings of an address space. They are only returned to the
+/// frame allocator once no CPU can reach them through its TLB.
+struct PendingFrames {
+    frames: Vec<Frame>,
+}
+
+impl PendingFrames {
+    fn new() -> PendingFrames {
+        PendingFrames { frames: Vec::new() }
+    }
+
+    /// Scrub the frames and return them to the zeroed frame pool, the TLB entries must be
+    /// invalidated on all CPUs.
+    fn release(self) {
+        for frame in self.frames {
+            frame_pool::release(frame);
+        }
+    }
+}
+
+impl FrameAllocator for PendingFrames {
+    fn allocate_frame(&mut self) -> Option<Frame> {
+        None
+    }
+
+    fn deallocate_frame(&mut self, frame: Frame) {
+        self.frames.push(frame);
+    }
+}
+
+impl AddressSpace {
+    /// Create a new address space with an empty user half.
+    ///
+    /// ## Returns
+    /// `None` when there is no frame left for the P4 table.
+    pub fn new() -> Option<AddressSpace> {
+        let p4_frame = match GlobalFrameAllocator.allocate_frame() {
+            Some(frame) => frame,
+            None => return None,
+        };
+        stats::frames_allocated(FrameUse::PageTable, 1);
+
+        let mut address_space = AddressSpace {
+            p4_frame: p4_frame,
+            regions: Vec::new(),
+            pcid: pcid::allocate(),
+            cpus: AtomicUsize::new(0),
+            lock: AtomicBool::new(false),
+        };
+
+        {
+            let current_p4_frame = Frame::containing_address(pcid::current_p4_address());
+            let current_p4 = unsafe {
+                &*(current_p4_frame.virtual_address() as *const Table<Level4>)
+            };
+            let p4_frame = address_space.p4_frame.clone();
+            let p4 = address_space.p4_mut();
+
+            p4.zero();
+
+            // share the kernel half with the current address space
+            for index in KERNEL_P4_INDEX..ENTRY_COUNT {
+                if let Some(frame) = current_p4[index].pointed_frame() {
+                    p4[index].set(frame, current_p4[index].flags());
+                }
+            }
+
+            // set up recursive mapping for the table
+            p4[RECURSIVE_INDEX].set(p4_frame, PRESENT | WRITABLE);
+        }
+
+        Some(address_space)
+    }
+
+    /// Physical frame of the P4 table.
+    pub fn p4_frame(&self) -> &Frame {
+        &self.p4_frame
+    }
+
+    /// Mapped regions sorted by the start address.
+    pub fn regions(&self) -> &[Region] {
+        &self.regions
+    }
+
+    /// Find the region that contains the given address.
+    pub fn find_region(&self, address: VirtualAddress) -> Option<&Region> {
+        self.regions.iter().find(|region| region.contains(address))
+    }
+
+    /// Check if this is the address space loaded on the current CPU.
+    pub fn is_active(&self) -> bool {
+        pcid::current_p4_address() == self.p4_frame.start_address()
+    }
+
+    /// Switch the current CPU to this address space.
+    ///
+    /// The address space must not be moved while it is active, since the page fault handler keeps
+    /// a pointer to it.
+    pub unsafe fn activate(&mut self) {
+        CURRENT[shootdown::current_cpu()].store(self as *mut _ as usize, Ordering::SeqCst);
+        self.cpus.fetch_or(shootdown::current_cpu_mask(), Ordering::SeqCst);
+
+        // the TLB entries of the PCID are invalidated on every CPU when the mappings change, so
+        // they are always up to date
+        match self.pcid {
+            Some(pcid) => pcid::load_cr3(self.p4_frame.start_address(), pcid, true),
+            None => pcid::load_cr3(self.p4_frame.start_address(), pcid::KERNEL_PCID, false),
+        }
+    }
+
+    /// Reserve a region of `size` bytes starting at `start` without backing it. The frames are
+    /// allocated and zeroed by the page fault handler when the pages are accessed for the first
+    /// time. The `USER_ACCESSIBLE` flag is added by default.
+    pub fn reserve(&mut self,
+                   start: VirtualAddress,
+                   size: usize,
+                   flags: EntryFlags)
+                   -> Result<(), RegionError> {
+        let region = Region {
+            start: start,
+            size: size,
+            flags: flags | USER_ACCESSIBLE,
+        };
+
+        self.locked(|address_space| {
+            let index = address_space.insert_position(&region)?;
+            address_space.regions.insert(index, region);
+
+            Ok(())
+        })
+    }
+
+    /// Map a region of `size` bytes starting at `start` to newly allocated zeroed frames. The
+    /// `USER_ACCESSIBLE` flag is added by default.
+    pub fn map(&mut self,
+               start: VirtualAddress,
+               size: usize,
+               flags: EntryFlags)
+               -> Result<(), RegionError> {
+        self.locked(|address_space| address_space.map_region(start, size, flags))
+    }
+
+    /// Add the region and back it, the lock must be held.
+    fn map_region(&mut self,
+                  start: VirtualAddress,
+                  size: usize,
+                  flags: EntryFlags)
+                  -> Result<(), RegionError> {
+        let region = Region {
+            start: start,
+            size: size,
+            flags: flags | USER_ACCESSIBLE,
+        };
+
+        let index = self.insert_position(&region)?;
+        self.regions.insert(index, region);
+
+        let mut allocator = GlobalFrameAllocator;
+        for page in region.pages() {
+            let mapped = match frame_pool::allocate_zeroed() {
+                Some(frame) => {
+                    stats::frames_allocated(FrameUse::User, 1);
+                    let mapped = self.map_page(page, &frame, region.flags, &mut allocator);
+                    if mapped.is_err() {
+                        frame_pool::release(frame);
+                        stats::frames_freed(FrameUse::User, 1);
+                    }
+                    mapped
+                }
+                None => Err(RegionError::OutOfMemory),
+            };
+
+            if mapped.is_err() {
+                // undo the pages mapped so far
+                self.unmap_region(start).unwrap();
+                return mapped;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Unmap the region starting at `start` and free its frames.
+    pub fn unmap(&mut self, start: VirtualAddress) -> Result<(), RegionError> {
+        self.locked(|address_space| address_space.unmap_region(start))
+    }
+
+    /// Remove the region and free its frames, the lock must be held.
+    fn unmap_region(&mut self, start: VirtualAddress) -> Result<(), RegionError> {
+        let index = match self.regions.iter().position(|region| region.start == start) {
+            Some(index) => index,
+            None => return Err(RegionError::NotMapped),
+        };
+
+        let region = self.regions.remove(index);
+        let mut pending = PendingFrames::new();
+        self.unmap_pages(&region, &mut pending);
+        self.free_empty_tables(&mut pending);
+
+        self.flush();
+        pending.release();
+
+        Ok(())
+    }
+
+    /// Create a copy of the address space that shares all the mapped frames. The writable pages are
+    /// mapped read-only with the `COPY_ON_WRITE` flag on both address spaces, and are copied on the
+    /// first write.
+    ///
+    /// ## Returns
+    /// `None` when there is no frame left for the P4 table or the page tables of the copy.
+    pub fn duplicate(&mut self) -> Option<AddressSpace> {
+        let mut child = match AddressSpace::new() {
+            Some(address_space) => address_space,
+            None => return None,
+        };
+
+        // the pages already shared with the child are released when it is dropped
+        match self.locked(|address_space| address_space.share_regions(&mut child)) {
+            Ok(()) => Some(child),
+            Err(_) => None,
+        }
+    }
+
+    /// Map all the frames of the address space on `child` and mark the writable pages as
+    /// copy-on-write, the lock must be held.
+    ///
+    /// ## Returns
+    /// `RegionError::OutOfMemory` when there is no frame left for the page tables of `child`, the
+    /// pages mapped so far stay on `child`.
+    fn share_regions(&mut self, child: &mut AddressSpace) -> Result<(), RegionError> {
+        let mut allocator = GlobalFrameAllocator;
+        let regions = self.regions.clone();
+
+        // the child owns the regions from the start, so it unmaps the shared pages when dropped
+        child.regions = regions.clone();
+
+        let mut result = Ok(());
+        'regions: for region in &regions {
+            for page in region.pages() {
+                let (frame, flags) = match self.entry_mut(page) {
+                    Some(entry) => {
+                        let frame = match entry.pointed_frame() {
+                            Some(frame) => frame,
+                            // the page was not backed yet
+                            None => continue,
+                        };
+
+                        let mut flags = entry.flags();
+                        if flags.contains(WRITABLE) {
+                            flags = (flags - WRITABLE) | COPY_ON_WRITE;
+                            entry.set(frame.clone(), flags);
+                        }
+
+                        (frame, flags)
+                    }
+                    None => continue,
+                };
+
+                frame_refs::acquire(&frame);
+                if let Err(error) = child.map_page(page, &frame, flags, &mut allocator) {
+                    // the parent keeps its reference, so this can't be the last one
+                    frame_refs::release(&frame);
+                    result = Err(error);
+                    break 'regions;
+                }
+            }
+        }
+
+        // the writable pages of this address space are now read-only
+        self.flush();
+        result
+    }
+
+    /// Run `f` with the lock of the address space held. While waiting the shootdown requests are
+    /// served, the CPU holding the lock can be waiting for this one with the interrupts disabled.
+    fn locked<F, R>(&mut self, f: F) -> R
+        where F: FnOnce(&mut AddressSpace) -> R
+    {
+        while self.lock.compare_and_swap(false, true, Ordering::Acquire) {
+            shootdown::handle_ipi();
+        }
+
+        let result = f(self);
+
+        self.lock.store(false, Ordering::Release);
+        result
+    }
+
+    /// Back the page containing `address` if it is part of a region and the access is allowed by
+    /// the region flags.
+    ///
+    /// ## Returns
+    /// `true` if the fault was resolved.
+    fn handle_page_fault(&mut self,
+                         address: VirtualAddress,
+                         error_code: PageFaultErrorCode)
+                         -> bool {
+        let flags = match self.find_region(address) {
+            Some(region) => region.flags,
+            None => return false,
+        };
+
+        // the page is present, so this is an access violation unless it is a copy-on-write page
+        if error_code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+            if error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) &&
+               flags.contains(WRITABLE) {
+                return self.copy_on_write(Page::containing_address(address));
+            }
+            return false;
+        }
+        if error_code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) && !flags.contains(WRITABLE) {
+            return false;
+        }
+        if error_code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) &&
+           flags.contains(NO_EXECUTE) {
+            return false;
+        }
+
+        let mut allocator = GlobalFrameAllocator;
+        let frame = match frame_pool::allocate_zeroed() {
+            Some(frame) => frame,
+            None => return false,
+        };
+        stats::frames_allocated(FrameUse::User, 1);
+
+        // the fault stays unresolved when there is no frame left for the page tables
+        let page = Page::containing_address(address);
+        if self.map_page(page, &frame, flags, &mut allocator).is_err() {
+            frame_pool::release(frame);
+            stats::frames_freed(FrameUse::User, 1);
+            return false;
+        }
+        true
+    }
+
+    /// Give the address space its own writable copy of a copy-on-write page.
+    ///
+    /// ## Returns
+    /// `true` if the page was a copy-on-write page and is now writable.
+    fn copy_on_write(&mut self, page: Page) -> bool {
+        use core::ptr;
+
+        // the frame shared with other mappings, `None` when the page is now the only mapping
+        let shared_frame = {
+            let entry = match self.entry_mut(page) {
+                Some(entry) => entry,
+                None => return false,
+            };
+
+            if !entry.flags().contains(COPY_ON_WRITE) {
+                return false;
+            }
+
+            let frame = entry.pointed_frame().unwrap();
+            let flags = (entry.flags() - COPY_ON_WRITE) | WRITABLE;
+
+            if frame_refs::count(&frame) == 1 {
+                // the other mappings are gone, the frame can be used in place
+                entry.set(frame, flags);
+                None
+            } else {
+                let copy = match GlobalFrameAllocator.allocate_frame() {
+                    Some(frame) => frame,
+                    None => return false,
+                };
+                stats::frames_allocated(FrameUse::User, 1);
+
+                unsafe {
+                    ptr::copy_nonoverlapping(frame.virtual_address() as *const u8,
+                                             copy.virtual_address() as *mut u8,
+                                             PAGE_SIZE);
+                }
+
+                entry.set(copy, flags);
+                Some(frame)
+            }
+        };
+
+        // the other CPUs running the address space must stop using the shared frame before it is
+        // released
+        pcid::flush_address(self.pcid.unwrap_or(pcid::KERNEL_PCID), page.start_address());
+        shootdown::flush_remote(self.cpus.load(Ordering::SeqCst),
+                                Some(page.start_address()),
+                                self.pcid);
+
+        // the other mappings can have copied the frame meanwhile, the last one frees it
+        if let Some(frame) = shared_frame {
+            if frame_refs::release(&frame) {
+                frame_pool::release(frame);
+                stats::frames_freed(FrameUse::User, 1);
+            }
+        }
+        true
+    }
+
+    /// Check if the region can be added to the address space.
+    ///
+    /// ## Returns
+    /// The position where the region must be inserted to keep the list sorted.
+    fn insert_position(&self, region: &Region) -> Result<usize, RegionError> {
+        if region.size == 0 || region.start % PAGE_SIZE != 0 ||
+           region.size % PAGE_SIZE != 0 {
+            return Err(RegionError::InvalidRange);
+        }
+
+        if region.start >= USER_END || USER_END - region.start < region.size {
+            return Err(RegionError::NotUserAddress);
+        }
+
+        let index = self.regions
+            .iter()
+            .position(|other| other.start >= region.start)
+            .unwrap_or(self.regions.len());
+
+        let overlaps_previous = index > 0 && self.regions[index - 1].end() > region.start;
+        let overlaps_next = index < self.regions.len() &&
+                            self.regions[index].start < region.end();
+        if overlaps_previous || overlaps_next {
+            return Err(RegionError::Overlap);
+        }
+
+        Ok(index)
+    }
+
+    /// Invalidate the TLB entries of the address space on every CPU that used it, after its
+    /// mappings changed.
+    fn flush(&mut self) {
+        use x86_64::instructions::tlb;
+
+        match self.pcid {
+            Some(pcid) => pcid::flush_context(pcid),
+            // without PCID the entries are dropped when another page table is loaded
+            None if self.is_active() => unsafe { tlb::flush_all() },
+            None => {}
+        }
+
+        shootdown::flush_remote(self.cpus.load(Ordering::SeqCst), None, self.pcid);
+    }
+
+    /// Access the P4 table through the physical memory direct map.
+    fn p4_mut(&mut self) -> &mut Table<Level4> {
+        unsafe { &mut *(self.p4_frame.virtual_address() as *mut Table<Level4>) }
+    }
+
+    /// Map a page of the user half to the given frame. The frame still belongs to the caller when
+    /// the mapping fails.
+    ///
+    /// ## Returns
+    /// `RegionError::OutOfMemory` when there is no frame left for the page tables.
+    fn map_page<A>(&mut self,
+                   page: Page,
+                   frame: &Frame,
+                   flags: EntryFlags,
+                   allocator: &mut A)
+                   -> Result<(), RegionError>
+        where A: FrameAllocator
+    {
+        // the user access must be allowed on all levels
+        let table_flags = WRITABLE | USER_ACCESSIBLE;
+
+        let p3 = match self.p4_mut()
+            .try_next_table_direct_create(page.p4_index(), table_flags, allocator) {
+            Some(p3) => p3,
+            None => return Err(RegionError::OutOfMemory),
+        };
+        let p2 = match p3.try_next_table_direct_create(page.p3_index(), table_flags, allocator) {
+            Some(p2) => p2,
+            None => return Err(RegionError::OutOfMemory),
+        };
+        let p1 = match p2.try_next_table_direct_create(page.p2_index(), table_flags, allocator) {
+            Some(p1) => p1,
+            None => return Err(RegionError::OutOfMemory),
+        };
+
+        assert!(p1[page.p1_index()].is_unused());
+        p1[page.p1_index()].set(frame.clone(), flags | PRESENT);
+        Ok(())
+    }
+
+    /// Returns the P1 entry of a page of the user half, or `None` if there is no P1 table for it.
+    fn entry_mut(&mut self, page: Page) -> Option<&mut Entry> {
+        self.p4_mut()
+            .next_table_direct_mut(page.p4_index())
+            .and_then(|p3| p3.next_table_direct_mut(page.p3_index()))
+            .and_then(|p2| p2.next_table_direct_mut(page.p2_index()))
+            .map(|p1| &mut p1[page.p1_index()])
+    }
+
+    /// Unmap a page of the user half.
+    ///
+    /// ## Returns
+    /// The frame the page was mapped to, or `None` when the page wasn't mapped.
+    fn unmap_page(&mut self, page: Page) -> Option<Frame> {
+        self.entry_mut(page).and_then(|entry| {
+            let frame = entry.pointed_frame();
+            entry.set_unused();
+            frame
+        })
+    }
+
+    /// Unmap all the pages of the region. The frames that aren't shared with other mappings are
+    /// added to `pending`.
+    fn unmap_pages(&mut self, region: &Region, pending: &mut PendingFrames) {
+        for page in region.pages() {
+            if let Some(frame) = self.unmap_page(page) {
+                if frame_refs::release(&frame) {
+                    pending.deallocate_frame(frame);
+                    stats::frames_freed(FrameUse::User, 1);
+                }
+            }
+        }
+    }
+
+    /// Free all the page tables of the user half that don't have any used entry, their frames are
+    /// added to `pending`.
+    fn free_empty_tables(&mut self, pending: &mut PendingFrames) {
+        let p4 = self.p4_mut();
+
+        for p4_index in 0..KERNEL_P4_INDEX {
+            if let Some(p3) = p4.next_table_direct_mut(p4_index) {
+                for p3_index in 0..ENTRY_COUNT {
+                    if let Some(p2) = p3.next_table_direct_mut(p3_index) {
+                        for p2_index in 0..ENTRY_COUNT {
+                            p2.free_next_table_direct_if_empty(p2_index, pending);
+                        }
+                    }
+                    p3.free_next_table_direct_if_empty(p3_index, pending);
+                }
+            }
+            p4.free_next_table_direct_if_empty(p4_index, pending);
+        }
+    }
+}
+
+impl Drop for AddressSpace {
+    fn drop(&mut self) {
+        assert!(!self.is_active(), "the active address space can't be destroyed");
+
+        // the page fault handler of every CPU must not use this address space anymore
+        for current in CURRENT.iter() {
+            current.compare_and_swap(self as *mut _ as usize, 0, Ordering::SeqCst);
+        }
+
+        let mut pending = PendingFrames::new();
+        while let Some(region) = self.regions.pop() {
+            self.unmap_pages(&region, &mut pending);
+        }
+        self.free_empty_tables(&mut pending);
+
+        // the other CPUs can still cache the entries of the address space
+        self.flush();
+        pending.release();
+
+        if let Some(pcid) = self.pcid {
+            pcid::free(pcid);
+        }
+
+        GlobalFrameAllocator.deallocate_frame(self.p4_frame.clone());
+        stats::frames_freed(FrameUse::PageTable, 1);
+    }
+}
@@ -41,6 +41,8 @@ bitflags! {
         const DIRTY =           1 << 6,
         const HUGE_PAGE =       1 << 7,
         const GLOBAL =          1 << 8,
+        // bits 9 to 11 and 52 to 62 are available to the OS
+        const COPY_ON_WRITE =   1 << 9,
         const NO_EXECUTE =      1 << 63,
     }
 }
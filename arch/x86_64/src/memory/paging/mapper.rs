@@ -1,6 +1,8 @@
-use super::{VirtualAddress, PhysicalAddress, Page, ENTRY_COUNT};
+use super::{VirtualAddress, PhysicalAddress, Page, PageSize, ENTRY_COUNT};
+use super::{KERNEL_P4_INDEX, RECURSIVE_INDEX};
 use super::entry::*;
 use super::table::{self, Table, Level4};
+use super::{address_space, pcid, shootdown};
 use memory::{PAGE_SIZE, Frame, FrameAllocator};
 use core::ptr::Unique;
 
@@ -67,6 +69,33 @@ impl Mapper {
             .or_else(huge_page)
     }
 
+    /// Returns the size of the page used to map the given page, or `None` if it is not mapped.
+    pub fn translate_page_size(&self, page: Page) -> Option<PageSize> {
+        let p3 = match self.p4().next_table(page.p4_index()) {
+            Some(p3) => p3,
+            None => return None,
+        };
+
+        let p3_entry = &p3[page.p3_index()];
+        if p3_entry.flags().contains(PRESENT | HUGE_PAGE) {
+            return Some(PageSize::Giant);
+        }
+
+        let p2 = match p3.next_table(page.p3_index()) {
+            Some(p2) => p2,
+            None => return None,
+        };
+
+        let p2_entry = &p2[page.p2_index()];
+        if p2_entry.flags().contains(PRESENT | HUGE_PAGE) {
+            return Some(PageSize::Huge);
+        }
+
+        p2.next_table(page.p2_index())
+            .and_then(|p1| p1[page.p1_index()].pointed_frame())
+            .map(|_| PageSize::Normal)
+    }
+
     /// Maps the page to the frame with the provided flags.
     /// The `PRESENT` flag is added by default. Needs a `FrameAllocator` as it might need to create
     /// new page tables.
@@ -81,6 +110,52 @@ impl Mapper {
         p1[page.p1_index()].set(frame, flags | PRESENT);
     }
 
+    /// Maps the page to the frame using a page of the given size. Both the page and the frame must
+    /// be aligned to the page size. The `PRESENT` flag is added by default, and the `HUGE_PAGE`
+    /// flag for 2MiB and 1GiB pages.
+    pub fn map_to_sized<A>(&mut self,
+                           page: Page,
+                           frame: Frame,
+                           size: PageSize,
+                           flags: EntryFlags,
+                           allocator: &mut A)
+        where A: FrameAllocator
+    {
+        assert!(page.number % size.page_count() == 0, "page is not {:?} aligned", size);
+        assert!(frame.number % size.page_count() == 0, "frame is not {:?} aligned", size);
+
+        match size {
+            PageSize::Normal => self.map_to(page, frame, flags, allocator),
+            PageSize::Huge => {
+                let mut p3 = self.p4_mut().next_table_create(page.p4_index(), allocator);
+                let mut p2 = p3.next_table_create(page.p3_index(), allocator);
+
+                assert!(p2[page.p2_index()].is_unused());
+                p2[page.p2_index()].set(frame, flags | PRESENT | HUGE_PAGE);
+            }
+            PageSize::Giant => {
+                assert!(size.is_supported(), "1GiB pages are not supported by the CPU");
+
+                let mut p3 = self.p4_mut().next_table_create(page.p4_index(), allocator);
+
+                assert!(p3[page.p3_index()].is_unused());
+                p3[page.p3_index()].set(frame, flags | PRESENT | HUGE_PAGE);
+            }
+        }
+    }
+
+    /// Creates the P3 tables for all the P4 entries of the kernel half. This way the kernel half of
+    /// the P4 table never changes and can be shared by every address space.
+    pub fn create_kernel_tables<A>(&mut self, allocator: &mut A)
+        where A: FrameAllocator
+    {
+        for index in KERNEL_P4_INDEX..ENTRY_COUNT {
+            if index != RECURSIVE_INDEX {
+                self.p4_mut().next_table_create(index, allocator);
+            }
+        }
+    }
+
     /// Maps the page to some free frame with the provided flags.
     /// The free frame is allocated from the given `FrameAllocator`.
     pub fn map<A>(&mut self, page: Page, flags: EntryFlags, allocator: &mut A)
@@ -102,24 +177,121 @@ impl Mapper {
     /// Unmaps the given page and adds all freed frames to the given `FrameAllocator`.
     pub fn unmap<A>(&mut self, page: Page, allocator: &mut A)
         where A: FrameAllocator
+    {
+        let frame = self.unmap_return(page, allocator);
+        allocator.deallocate_frame(frame);
+    }
+
+    /// Unmaps the given page and returns the frame it was mapped to instead of freeing it. Page
+    /// tables that become empty are still returned to the given `FrameAllocator`.
+    ///
+    /// This must be used when the frame isn't owned by the mapping, like the frames of the kernel
+    /// image or frames mapped through a `TemporaryPage`.
+    pub fn unmap_return<A>(&mut self, page: Page, allocator: &mut A) -> Frame
+        where A: FrameAllocator
+    {
+        self.unmap_sized(page, PageSize::Normal, allocator)
+    }
+
+    /// Unmaps a page of the given size and returns the first frame it was mapped to. The frames
+    /// aren't freed, page tables that become empty are returned to the given `FrameAllocator`.
+    pub fn unmap_sized<A>(&mut self, page: Page, size: PageSize, allocator: &mut A) -> Frame
+        where A: FrameAllocator
     {
         use x86_64::VirtualAddress;
         use x86_64::instructions::tlb;
 
-        assert!(self.translate(page.start_address()).is_some());
+        assert!(self.translate_page_size(page) == Some(size),
+                "page {:#x} is not mapped as a {:?} page", page.start_address(), size);
+
+        let frame = match size {
+            PageSize::Normal => {
+                let p1 = self.p4_mut()
+                    .next_table_mut(page.p4_index())
+                    .and_then(|p3| p3.next_table_mut(page.p3_index()))
+                    .and_then(|p2| p2.next_table_mut(page.p2_index()))
+                    .unwrap();
+                let frame = p1[page.p1_index()].pointed_frame().unwrap();
+                p1[page.p1_index()].set_unused();
+                frame
+            }
+            PageSize::Huge => {
+                let p2 = self.p4_mut()
+                    .next_table_mut(page.p4_index())
+                    .and_then(|p3| p3.next_table_mut(page.p3_index()))
+                    .unwrap();
+                let frame = p2[page.p2_index()].pointed_frame().unwrap();
+                p2[page.p2_index()].set_unused();
+                frame
+            }
+            PageSize::Giant => {
+                let p3 = self.p4_mut().next_table_mut(page.p4_index()).unwrap();
+                let frame = p3[page.p3_index()].pointed_frame().unwrap();
+                p3[page.p3_index()].set_unused();
+                frame
+            }
+        };
+
+        // flush page address from the TLB, this invalidates the whole page whatever its size is.
+        // The kernel half is shared, so it can be cached by the PCID of any address space
+        if page.p4_index() >= KERNEL_P4_INDEX {
+            pcid::flush_address_all_contexts(page.start_address());
+        } else {
+            tlb::flush(VirtualAddress(page.start_address()));
+        }
+
+        // the other CPUs can also cache the page, the frame can't be reused before they drop it.
+        // The kernel half can be cached by any CPU, the user half only by the CPUs that ran the
+        // address space
+        if page.p4_index() >= KERNEL_P4_INDEX {
+            shootdown::flush_remote(shootdown::ALL_CPUS, Some(page.start_address()), None);
+        } else if let Some((cpus, pcid)) = address_space::active_user_cpus() {
+            shootdown::flush_remote(cpus, Some(page.start_address()), pcid);
+        }
 
-        let p1 = self.p4_mut()
-            .next_table_mut(page.p4_index())
-            .and_then(|p3| p3.next_table_mut(page.p3_index()))
-            .and_then(|p2| p2.next_table_mut(page.p2_index()))
-            .expect("mapping code does not support huge pages");
-        let frame = p1[page.p1_index()].pointed_frame().unwrap();
-        p1[page.p1_index()].set_unused();
+        self.free_empty_tables(page, size, allocator);
+
+        frame
+    }
+
+    /// Free the P1, P2 and P3 tables used to map the given page when they no longer have any used
+    /// entry.
+    fn free_empty_tables<A>(&mut self, page: Page, size: PageSize, allocator: &mut A)
+        where A: FrameAllocator
+    {
+        let (table_freed, p2_freed) = {
+            let p3 = self.p4_mut()
+                .next_table_mut(page.p4_index())
+                .expect("P3 table not present");
+
+            match size {
+                PageSize::Normal => {
+                    let p1_freed = {
+                        let p2 = p3.next_table_mut(page.p3_index())
+                            .expect("P2 table not present");
+                        p2.free_next_table_if_empty(page.p2_index(), allocator)
+                    };
+
+                    (p1_freed, p1_freed && p3.free_next_table_if_empty(page.p3_index(), allocator))
+                }
+                PageSize::Huge => {
+                    let p2_freed = p3.free_next_table_if_empty(page.p3_index(), allocator);
+                    (p2_freed, p2_freed)
+                }
+                // the page was mapped directly by the P3 table
+                PageSize::Giant => (false, true),
+            }
+        };
 
-        // flush page address from the TLB
-        tlb::flush(VirtualAddress(page.start_address()));
+        // the P3 tables of the kernel half are shared by all the address spaces
+        if p2_freed && page.p4_index() < KERNEL_P4_INDEX {
+            self.p4_mut().free_next_table_if_empty(page.p4_index(), allocator);
+        }
 
-        // TODO free p(1,2,3) table if empty
-        // allocator.deallocate_frame(frame);
+        // the freed tables of the kernel half can be cached by the PCID of any address space
+        if table_freed && page.p4_index() >= KERNEL_P4_INDEX {
+            pcid::flush_all_contexts();
+            shootdown::flush_remote(shootdown::ALL_CPUS, None, None);
+        }
     }
 }
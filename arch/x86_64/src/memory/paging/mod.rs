@@ -2,19 +2,36 @@
 //! Some code was borrowed from [Phil Opp's Blog](http://os.phil-opp.com/modifying-page-tables.html)
 
 pub use self::entry::*;
-use memory::{PAGE_SIZE, Frame, FrameAllocator};
+use memory::{PAGE_SIZE, KERNEL_OFFSET, Frame, FrameAllocator};
+use memory::kernel_virt_to_phys;
+use memory::stack_allocator::register_stack;
+use memory::stats::{self, FrameUse};
 use self::temporary_page::TemporaryPage;
 pub use self::mapper::Mapper;
+pub use self::address_space::{AddressSpace, Region, RegionError, handle_page_fault};
+pub use self::address_space::{check_user_range, USER_END};
 use core::ops::{Add, Deref, DerefMut};
 use multiboot2::BootInformation;
 
+mod address_space;
 pub mod entry;
 mod mapper;
+pub mod pcid;
+pub mod shootdown;
 mod table;
 mod temporary_page;
 
 const ENTRY_COUNT: usize = 512;
 
+/// P4 entry used for the recursive mapping, the last entry is used by the kernel image.
+pub const RECURSIVE_INDEX: usize = 510;
+
+/// First P4 entry of the kernel half of the address space.
+pub const KERNEL_P4_INDEX: usize = ENTRY_COUNT / 2;
+
+/// Address of the page used by the `TemporaryPage` to edit inactive page tables.
+const TEMPORARY_PAGE_ADDRESS: VirtualAddress = 0o_177777_775_000_000_000_0000;
+
 pub type PhysicalAddress = usize;
 pub type VirtualAddress = usize;
 
@@ -56,6 +73,49 @@ impl Page {
     }
 }
 
+/// Size of the pages that can be mapped by the `Mapper`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PageSize {
+    /// 4KiB page, mapped by a P1 entry
+    Normal,
+    /// 2MiB page, mapped by a P2 entry
+    Huge,
+    /// 1GiB page, mapped by a P3 entry
+    Giant,
+}
+
+impl PageSize {
+    /// Number of 4KiB pages covered by a page of this size.
+    pub fn page_count(&self) -> usize {
+        match *self {
+            PageSize::Normal => 1,
+            PageSize::Huge => ENTRY_COUNT,
+            PageSize::Giant => ENTRY_COUNT * ENTRY_COUNT,
+        }
+    }
+
+    /// Size of the page in bytes.
+    pub fn bytes(&self) -> usize {
+        self.page_count() * PAGE_SIZE
+    }
+
+    /// Check if the CPU supports pages of this size.
+    ///
+    /// 2MiB pages are always available on long mode, 1GiB pages depend on the `pdpe1gb` CPUID flag.
+    pub fn is_supported(&self) -> bool {
+        use raw_cpuid::CpuId;
+
+        match *self {
+            PageSize::Giant => {
+                CpuId::new()
+                    .get_extended_function_info()
+                    .map_or(false, |info| info.has_1gib_pages())
+            }
+            _ => true,
+        }
+    }
+}
+
 impl Add<usize> for Page {
     type Output = Page;
 
@@ -123,7 +183,7 @@ impl ActivePageTable {
             let p4_table = temporary_page.map_table_frame(backup.clone(), self);
 
             // overwrite recursive mapping and flush the tlb to ensure the correct page translation
-            self.p4_mut()[511].set(table.p4_frame.clone(), PRESENT | WRITABLE);
+            self.p4_mut()[RECURSIVE_INDEX].set(table.p4_frame.clone(), PRESENT | WRITABLE);
 
             // flush TLB
             self.flush_all();
@@ -132,7 +192,7 @@ impl ActivePageTable {
             f(self);
 
             // restore recursive mapping to original p4 table and flush the tlb again
-            p4_table[511].set(backup, PRESENT | WRITABLE);
+            p4_table[RECURSIVE_INDEX].set(backup, PRESENT | WRITABLE);
 
             // flush TLB
             self.flush_all();
@@ -143,17 +203,14 @@ impl ActivePageTable {
 
     /// Switch context
     pub fn switch(&mut self, new_table: InactivePageTable) -> InactivePageTable {
-        use x86_64::PhysicalAddress;
-        use x86_64::registers::control_regs;
-
         // store the ond table
         let old_table = InactivePageTable {
-            p4_frame: Frame::containing_address(control_regs::cr3().0 as usize),
+            p4_frame: Frame::containing_address(pcid::current_p4_address()),
         };
 
-        // switch to the new page table
+        // switch to the new page table, the inactive tables don't have their own PCID
         unsafe {
-            control_regs::cr3_write(PhysicalAddress(new_table.p4_frame.start_address() as u64));
+            pcid::load_cr3(new_table.p4_frame.start_address(), pcid::KERNEL_PCID, false);
         }
 
         // return the old table
@@ -185,7 +242,7 @@ impl InactivePageTable {
             table.zero();
 
             // set up recursive mapping for the table
-            table[511].set(frame.clone(), PRESENT | WRITABLE);
+            table[RECURSIVE_INDEX].set(frame.clone(), PRESENT | WRITABLE);
         }
         temporary_page.unmap(active_table);
 
@@ -193,17 +250,94 @@ impl InactivePageTable {
     }
 }
 
+/// Map all the usable memory areas of the multiboot memory map and the ACPI memory at
+/// `physical_memory_offset()`. The 2MiB chunks inside of an area use 2MiB pages, the unaligned
+/// ends of the areas fall back to 4KiB pages. The ACPI memory is mapped so the ACPI tables can be
+/// read and the reclaimable frames can be used once they are handed back.
+fn map_physical_memory<A>(mapper: &mut Mapper, boot_info: &BootInformation, allocator: &mut A)
+    where A: FrameAllocator
+{
+    use memory::reserved::{self, ReservedKind};
+
+    let memory_map_tag = boot_info.memory_map_tag().expect("Memory map tag required");
+
+    for area in memory_map_tag.memory_areas() {
+        let end = (area.base_addr + area.length) as usize;
+        map_physical_range(mapper, area.base_addr as usize, end, allocator);
+    }
+
+    for range in reserved::ranges().iter() {
+        if range.kind == ReservedKind::AcpiReclaimable || range.kind == ReservedKind::AcpiNvs {
+            map_physical_range(mapper, range.start, range.end, allocator);
+        }
+    }
+}
+
+/// Map the physical range between `start` and `end` (exclusive) on the direct map. The 2MiB chunks
+/// that are entirely inside of the range use 2MiB pages and the unaligned head and tail use 4KiB
+/// pages, so the holes next to the range, which can hold device memory, are never mapped.
+fn map_physical_range<A>(mapper: &mut Mapper,
+                         start: PhysicalAddress,
+                         end: PhysicalAddress,
+                         allocator: &mut A)
+    where A: FrameAllocator
+{
+    let huge_page_count = PageSize::Huge.page_count();
+
+    // round the range to the frames it touches
+    let end = (end + PAGE_SIZE - 1) / PAGE_SIZE;
+    let mut number = start / PAGE_SIZE;
+
+    while number < end {
+        let frame = Frame { number: number };
+        let page = Page::containing_address(frame.virtual_address());
+
+        let size = if number % huge_page_count == 0 && number + huge_page_count <= end {
+            PageSize::Huge
+        } else {
+            PageSize::Normal
+        };
+
+        // two areas can share the same frame
+        if mapper.translate_page(page).is_none() {
+            mapper.map_to_sized(page, frame, size, WRITABLE | NO_EXECUTE, allocator);
+        }
+
+        number += size.page_count();
+    }
+}
+
+extern {
+    /// P4 table used by the boot code, defined on `boot.asm`
+    static p4_table: u8;
+    /// Top of the boot stack, defined on `boot.asm`
+    static stack_top: u8;
+}
+
+/// Register the boot stack, so overflows are reported. The page of the boot P4 table is unmapped
+/// by `remap_the_kernel` and the stack is placed after the boot page tables, which are no longer
+/// used, so the guard page protects all of them.
+///
+/// The stack registry lives on the heap, so this must be called after the heap is mapped.
+pub fn register_boot_stack() {
+    let guard_page = unsafe { &p4_table as *const _ as usize };
+    let boot_stack_top = unsafe { &stack_top as *const _ as usize };
+    register_stack("boot", boot_stack_top, guard_page + PAGE_SIZE);
+}
+
 /// Remap the kernel
 pub fn remap_the_kernel<A>(allocator: &mut A, boot_info: &BootInformation) -> ActivePageTable
     where A: FrameAllocator
 {
     use core::ops::Range;
 
-    let mut temporary_page = TemporaryPage::new(Page { number: 0xcafebabe }, allocator);
+    let mut temporary_page = TemporaryPage::new(Page::containing_address(TEMPORARY_PAGE_ADDRESS),
+                                                allocator);
 
     let mut active_table = unsafe { ActivePageTable::new() };
     let mut new_table = {
         let frame = allocator.allocate_frame().expect("no more frames");
+        stats::frames_allocated(FrameUse::PageTable, 1);
         InactivePageTable::new(frame, &mut active_table, &mut temporary_page)
     };
 
@@ -211,7 +345,7 @@ pub fn remap_the_kernel<A>(allocator: &mut A, boot_info: &BootInformation) -> Ac
         let elf_sections_tag = boot_info.elf_sections_tag()
             .expect("Memory map tag required");
 
-        // identity map the allocated kernel sections
+        // map the allocated kernel sections at the kernel offset
         for section in elf_sections_tag.sections() {
             if !section.is_allocated() {
                 // section is not loaded to memory
@@ -226,31 +360,43 @@ pub fn remap_the_kernel<A>(allocator: &mut A, boot_info: &BootInformation) -> Ac
 
             let flags = EntryFlags::from_elf_section_flags(section);
 
-            let start_frame = Frame::containing_address(section.start_address());
-            let end_frame = Frame::containing_address(section.end_address() - 1);
+            let start_address = kernel_virt_to_phys(section.start_address());
+            let end_address = kernel_virt_to_phys(section.end_address() - 1);
+
+            let start_frame = Frame::containing_address(start_address);
+            let end_frame = Frame::containing_address(end_address);
             for frame in Frame::range_inclusive(start_frame, end_frame) {
-                mapper.identity_map(frame, flags, allocator);
+                let page = Page::containing_address(frame.start_address() + KERNEL_OFFSET);
+                mapper.map_to(page, frame, flags, allocator);
             }
         }
 
-        // identity map the VGA text buffer
+        // map the VGA text buffer
         let vga_buffer_frame = Frame::containing_address(0xb8000);
-        mapper.identity_map(vga_buffer_frame, WRITABLE, allocator);
+        let vga_buffer_page = Page::containing_address(0xb8000 + KERNEL_OFFSET);
+        mapper.map_to(vga_buffer_page, vga_buffer_frame, WRITABLE, allocator);
 
-        // identity map the multiboot info structure
-        let multiboot_start = Frame::containing_address(boot_info.start_address());
-        let multiboot_end = Frame::containing_address(boot_info.end_address() - 1);
+        // map the multiboot info structure, it was loaded through the kernel offset
+        let multiboot_start = kernel_virt_to_phys(boot_info.start_address());
+        let multiboot_end = kernel_virt_to_phys(boot_info.end_address() - 1);
+
+        let multiboot_start = Frame::containing_address(multiboot_start);
+        let multiboot_end = Frame::containing_address(multiboot_end);
         for frame in Frame::range_inclusive(multiboot_start, multiboot_end) {
-            mapper.identity_map(frame, PRESENT, allocator);
+            let page = Page::containing_address(frame.start_address() + KERNEL_OFFSET);
+            mapper.map_to(page, frame, PRESENT, allocator);
         }
+
+        // map all the usable physical memory at the direct map offset
+        map_physical_memory(mapper, boot_info, allocator);
     });
 
     // switch context to start using the new page table
     let old_table = active_table.switch(new_table);
 
     // turn the old p4 page into a guard page
-    let old_p4_page = Page::containing_address(old_table.p4_frame.start_address());
-    active_table.unmap(old_p4_page, allocator);
+    let old_p4_page = Page::containing_address(old_table.p4_frame.start_address() + KERNEL_OFFSET);
+    active_table.unmap_return(old_p4_page, allocator);
     println!("guard page at {:#x}", old_p4_page.start_address());
 
     active_table
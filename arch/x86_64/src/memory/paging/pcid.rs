@@ -0,0 +1,194 @@
+//! # Process-Context Identifiers
+//!
+//! With PCIDs the TLB entries are tagged with the identifier of the address space that created
+//! them, so switching address spaces doesn't need to flush the TLB. PCIDs are only used when the
+//! CPU also supports `invpcid`, it is needed to invalidate the kernel half on every context and to
+//! clean a PCID before it is reused.
+//!
+//! The boot page table and the address spaces without their own PCID use `KERNEL_PCID`, their TLB
+//! entries are always flushed when they are loaded.
+
+use core::sync::atomic::{AtomicBool, Ordering};
+use raw_cpuid::CpuId;
+use spin::Mutex;
+
+use super::{PhysicalAddress, VirtualAddress};
+
+/// PCID shared by the page tables that don't have their own.
+pub const KERNEL_PCID: u16 = 0;
+
+/// Number of PCIDs supported by the CPU.
+const PCID_COUNT: usize = 4096;
+
+/// CR4 bit that enables PCIDs.
+const CR4_PCIDE: u64 = 1 << 17;
+
+/// CR3 bit that keeps the TLB entries of the loaded PCID.
+const CR3_NO_FLUSH: u64 = 1 << 63;
+
+/// Mask of the P4 table address on CR3.
+const CR3_ADDRESS_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+/// `invpcid` invalidation types.
+const INVPCID_ADDRESS: u64 = 0;
+const INVPCID_CONTEXT: u64 = 1;
+const INVPCID_ALL: u64 = 2;
+
+/// Set when PCIDs are enabled.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Bitmap of the PCIDs in use, a set bit means that the PCID is in use. It is taken with the
+/// interrupts enabled, so the shootdown IPI handler must never take it.
+static USED: Mutex<[u64; PCID_COUNT / 64]> = Mutex::new([0; PCID_COUNT / 64]);
+
+/// Enable PCIDs when they are supported by the CPU. It must be called while `KERNEL_PCID` is
+/// loaded.
+pub fn init() {
+    let cpuid = CpuId::new();
+    let has_pcid = cpuid.get_feature_info().map_or(false, |info| info.has_pcid());
+    let has_invpcid = cpuid.get_extended_feature_info().map_or(false, |info| info.has_invpcid());
+
+    if !has_pcid || !has_invpcid {
+        println!("PCID not supported, the TLB is flushed on every address space switch");
+        return;
+    }
+
+    unsafe {
+        let cr4: u64;
+        asm!("mov %cr4, $0" : "=r"(cr4));
+        asm!("mov $0, %cr4" :: "r"(cr4 | CR4_PCIDE) : "memory" : "volatile");
+    }
+
+    ENABLED.store(true, Ordering::SeqCst);
+}
+
+/// Check if PCIDs are enabled.
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Allocate a PCID for an address space.
+///
+/// ## Returns
+/// `None` when PCIDs aren't enabled or all of them are in use.
+pub fn allocate() -> Option<u16> {
+    if !is_enabled() {
+        return None;
+    }
+
+    let mut used = USED.lock();
+
+    // the kernel PCID is never handed out
+    let pcid = match (1..PCID_COUNT).find(|&pcid| used[pcid / 64] & 1 << (pcid % 64) == 0) {
+        Some(pcid) => pcid,
+        None => return None,
+    };
+
+    used[pcid / 64] |= 1 << (pcid % 64);
+    Some(pcid as u16)
+}
+
+/// Free a PCID allocated with `allocate`. Its TLB entries are invalidated, so it can be reused.
+pub fn free(pcid: u16) {
+    flush_context(pcid);
+    USED.lock()[pcid as usize / 64] &= !(1 << (pcid % 64));
+}
+
+/// Physical address of the P4 table loaded on CR3.
+pub fn current_p4_address() -> PhysicalAddress {
+    let cr3: u64;
+    unsafe { asm!("mov %cr3, $0" : "=r"(cr3)) };
+    (cr3 & CR3_ADDRESS_MASK) as PhysicalAddress
+}
+
+/// Load the P4 table at `p4_address` on CR3 with the given PCID.
+///
+/// ## Params
+/// * `keep_entries` - keep the TLB entries of the PCID, they must be up to date.
+pub unsafe fn load_cr3(p4_address: PhysicalAddress, pcid: u16, keep_entries: bool) {
+    let mut cr3 = p4_address as u64;
+    if is_enabled() {
+        cr3 |= pcid as u64;
+        if keep_entries && pcid != KERNEL_PCID {
+            cr3 |= CR3_NO_FLUSH;
+        }
+    }
+
+    asm!("mov $0, %cr3" :: "r"(cr3) : "memory" : "volatile");
+}
+
+/// Invalidate all the TLB entries of a PCID. Without PCIDs the entries of the current address
+/// space are invalidated.
+pub fn flush_context(pcid: u16) {
+    use x86_64::instructions::tlb;
+
+    if is_enabled() {
+        invpcid(INVPCID_CONTEXT, pcid, 0);
+    } else {
+        unsafe { tlb::flush_all() };
+    }
+}
+
+/// Invalidate the TLB entry of an address on a PCID. Without PCIDs the entry of the current address
+/// space is invalidated.
+pub fn flush_address(pcid: u16, address: VirtualAddress) {
+    use x86_64::instructions::tlb;
+
+    if is_enabled() {
+        invpcid(INVPCID_ADDRESS, pcid, address);
+    } else {
+        tlb::flush(::x86_64::VirtualAddress(address));
+    }
+}
+
+/// Invalidate the TLB entries of an address on every PCID in use. It is used for the kernel half,
+/// which is shared by all the address spaces.
+pub fn flush_address_all_contexts(address: VirtualAddress) {
+    use x86_64::instructions::tlb;
+
+    if !is_enabled() {
+        tlb::flush(::x86_64::VirtualAddress(address));
+        return;
+    }
+
+    let used = USED.lock();
+
+    invpcid(INVPCID_ADDRESS, KERNEL_PCID, address);
+    for pcid in 1..PCID_COUNT {
+        if used[pcid / 64] & 1 << (pcid % 64) != 0 {
+            invpcid(INVPCID_ADDRESS, pcid as u16, address);
+        }
+    }
+}
+
+/// Invalidate the TLB entries of an address on every PCID without looking at the PCIDs in use, so
+/// it can be called by the shootdown IPI handler. `invpcid` can't target one address on every
+/// context, so with PCIDs the whole TLB is invalidated.
+pub fn flush_address_any_context(address: VirtualAddress) {
+    use x86_64::instructions::tlb;
+
+    if is_enabled() {
+        invpcid(INVPCID_ALL, KERNEL_PCID, 0);
+    } else {
+        tlb::flush(::x86_64::VirtualAddress(address));
+    }
+}
+
+/// Invalidate the TLB entries of every PCID, including the global ones.
+pub fn flush_all_contexts() {
+    use x86_64::instructions::tlb;
+
+    if is_enabled() {
+        invpcid(INVPCID_ALL, KERNEL_PCID, 0);
+    } else {
+        unsafe { tlb::flush_all() };
+    }
+}
+
+/// Execute `invpcid` with the given type.
+fn invpcid(kind: u64, pcid: u16, address: VirtualAddress) {
+    let descriptor: [u64; 2] = [pcid as u64, address as u64];
+    unsafe {
+        asm!("invpcid ($0), $1" :: "r"(&descriptor), "r"(kind) : "memory" : "volatile");
+    }
+}
@@ -0,0 +1,117 @@
+//! # TLB Shootdown
+//!
+//! A CPU only invalidates its own TLB, so when a mapping changes the other CPUs that can have it
+//! cached are asked to invalidate it with an IPI. The CPU that changed the mapping waits for all
+//! of them to acknowledge before the frames are reused.
+//!
+//! CPUs are identified by their Local APIC ID, up to 64 CPUs are supported.
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+
+use device::local_apic::LOCAL_APIC;
+use super::{VirtualAddress, pcid};
+
+/// Mask that selects every online CPU.
+pub const ALL_CPUS: usize = !0;
+
+/// Maximum number of CPUs, the CPU masks have a bit for each of them.
+pub const MAX_CPUS: usize = 64;
+
+/// Value stored on the request when there is no address or PCID.
+const NONE: usize = !0;
+
+/// Mask of the CPUs that are online and can receive shootdown requests.
+static ONLINE_CPUS: AtomicUsize = AtomicUsize::new(0);
+
+/// Only one request can be in flight at a time.
+static REQUEST_LOCK: Mutex<()> = Mutex::new(());
+
+/// Address to invalidate, `NONE` to invalidate the whole context.
+static REQUEST_ADDRESS: AtomicUsize = AtomicUsize::new(NONE);
+
+/// PCID to invalidate, `NONE` for every PCID.
+static REQUEST_PCID: AtomicUsize = AtomicUsize::new(NONE);
+
+/// Mask of the CPUs that didn't acknowledge the request yet.
+static PENDING_CPUS: AtomicUsize = AtomicUsize::new(0);
+
+/// Mark the current CPU as online, it must be called once its Local APIC is initialized.
+pub fn cpu_online() {
+    let cpu = unsafe { LOCAL_APIC.id() };
+    assert!(cpu < MAX_CPUS, "CPU {} can't receive TLB shootdowns", cpu);
+    ONLINE_CPUS.fetch_or(1 << cpu, Ordering::SeqCst);
+}
+
+/// Index of the current CPU, its Local APIC ID.
+pub fn current_cpu() -> usize {
+    // before the Local APIC is initialized there is only the boot CPU
+    if ONLINE_CPUS.load(Ordering::SeqCst) == 0 {
+        return 0;
+    }
+
+    unsafe { LOCAL_APIC.id() }
+}
+
+/// Mask with the bit of the current CPU.
+pub fn current_cpu_mask() -> usize {
+    1 << current_cpu()
+}
+
+/// Ask the CPUs of `cpus` to invalidate their TLB entries and wait for all of them to acknowledge.
+/// The current CPU and the CPUs that aren't online are skipped, the current CPU must invalidate its
+/// own entries.
+///
+/// ## Params
+/// * `address` - address to invalidate, `None` for every address.
+/// * `pcid` - PCID whose entries are invalidated, `None` for every PCID.
+pub fn flush_remote(cpus: usize, address: Option<VirtualAddress>, pcid: Option<u16>) {
+    let targets = cpus & ONLINE_CPUS.load(Ordering::SeqCst) & !current_cpu_mask();
+    if targets == 0 {
+        return;
+    }
+
+    // serve the requests of the other CPUs while waiting, they could be waiting for this CPU with
+    // the interrupts disabled
+    let _lock = loop {
+        match REQUEST_LOCK.try_lock() {
+            Some(lock) => break lock,
+            None => handle_ipi(),
+        }
+    };
+
+    REQUEST_ADDRESS.store(address.unwrap_or(NONE), Ordering::SeqCst);
+    REQUEST_PCID.store(pcid.map_or(NONE, |pcid| pcid as usize), Ordering::SeqCst);
+    PENDING_CPUS.store(targets, Ordering::SeqCst);
+
+    for cpu in 0..MAX_CPUS {
+        if targets & 1 << cpu != 0 {
+            unsafe { LOCAL_APIC.inter_processor_interrupt(cpu) };
+        }
+    }
+
+    // the frames can only be reused once no CPU can reach them through a stale entry
+    while PENDING_CPUS.load(Ordering::SeqCst) != 0 {}
+}
+
+/// Handle a shootdown request, it is called by the IPI handler.
+pub fn handle_ipi() {
+    let mask = current_cpu_mask();
+    if PENDING_CPUS.load(Ordering::SeqCst) & mask == 0 {
+        return;
+    }
+
+    let address = REQUEST_ADDRESS.load(Ordering::SeqCst);
+    let request_pcid = REQUEST_PCID.load(Ordering::SeqCst);
+
+    // the PCID allocator lock can be held by the interrupted code, so the kernel half addresses are
+    // invalidated without it
+    match (address, request_pcid) {
+        (NONE, NONE) => pcid::flush_all_contexts(),
+        (NONE, request_pcid) => pcid::flush_context(request_pcid as u16),
+        (address, NONE) => pcid::flush_address_any_context(address),
+        (address, request_pcid) => pcid::flush_address(request_pcid as u16, address),
+    }
+
+    PENDING_CPUS.fetch_and(!mask, Ordering::SeqCst);
+}
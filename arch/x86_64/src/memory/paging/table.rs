@@ -10,10 +10,12 @@
 use memory::paging::entry::*;
 use memory::paging::ENTRY_COUNT;
 use memory::FrameAllocator;
+use memory::stats::{self, FrameUse};
 use core::ops::{Index, IndexMut};
 use core::marker::PhantomData;
 
-pub const P4: *mut Table<Level4> = 0xffffffff_fffff000 as *mut _;
+/// Address of the P4 table through the recursive mapping on the P4 entry `RECURSIVE_INDEX`.
+pub const P4: *mut Table<Level4> = 0xffffff7f_bfdfe000 as *mut _;
 
 pub struct Table<L: TableLevel> {
     entries: [Entry; ENTRY_COUNT],
@@ -27,6 +29,11 @@ impl<L> Table<L> where L: TableLevel
             entry.set_unused();
         }
     }
+
+    /// Check if all the entries of the table are unused.
+    pub fn is_empty(&self) -> bool {
+        self.entries.iter().all(|entry| entry.is_unused())
+    }
 }
 
 impl<L> Table<L> where L: HierarchicalLevel
@@ -35,7 +42,14 @@ impl<L> Table<L> where L: HierarchicalLevel
         let entry_flags = self[index].flags();
         if entry_flags.contains(PRESENT) && !entry_flags.contains(HUGE_PAGE) {
             let table_address = self as *const _ as usize;
-            Some((table_address << 9) | (index << 12))
+            let address = (table_address << 9) | (index << 12);
+
+            // the recursive entry isn't the last one, so the result must be sign extended
+            if address & (1 << 47) == 0 {
+                Some(address & 0x0000_ffff_ffff_ffff)
+            } else {
+                Some(address | 0xffff_0000_0000_0000)
+            }
         } else {
             None
         }
@@ -59,13 +73,113 @@ impl<L> Table<L> where L: HierarchicalLevel
     {
         if self.next_table(index).is_none() {
             assert!(!self.entries[index].flags().contains(HUGE_PAGE),
-                    "mapping code does not support huge pages");
+                    "the entry is already mapped as a huge page");
             let frame = allocator.allocate_frame().expect("no frames available");
+            stats::frames_allocated(FrameUse::PageTable, 1);
             self.entries[index].set(frame, PRESENT | WRITABLE);
             self.next_table_mut(index).unwrap().zero();
         }
         self.next_table_mut(index).unwrap()
     }
+
+    /// Returns the next level table accessing it through the physical memory direct map. Unlike
+    /// `next_table_mut` this also works for tables that aren't part of the active page table.
+    pub fn next_table_direct_mut(&mut self, index: usize) -> Option<&mut Table<L::NextLevel>> {
+        let entry_flags = self[index].flags();
+        if entry_flags.contains(PRESENT) && !entry_flags.contains(HUGE_PAGE) {
+            self[index].pointed_frame()
+                .map(|frame| unsafe { &mut *(frame.virtual_address() as *mut _) })
+        } else {
+            None
+        }
+    }
+
+    /// Same as `next_table_create`, but the tables are accessed through the physical memory direct
+    /// map. The new entry is created with the given flags.
+    pub fn next_table_direct_create<A>(&mut self,
+                                       index: usize,
+                                       flags: EntryFlags,
+                                       allocator: &mut A)
+                                       -> &mut Table<L::NextLevel>
+        where A: FrameAllocator
+    {
+        self.try_next_table_direct_create(index, flags, allocator).expect("no frames available")
+    }
+
+    /// Same as `next_table_direct_create`, but returns `None` instead of panicking when there is no
+    /// frame left for the new table.
+    pub fn try_next_table_direct_create<A>(&mut self,
+                                           index: usize,
+                                           flags: EntryFlags,
+                                           allocator: &mut A)
+                                           -> Option<&mut Table<L::NextLevel>>
+        where A: FrameAllocator
+    {
+        if self.next_table_direct_mut(index).is_none() {
+            assert!(!self.entries[index].flags().contains(HUGE_PAGE),
+                    "the entry is already mapped as a huge page");
+            let frame = match allocator.allocate_frame() {
+                Some(frame) => frame,
+                None => return None,
+            };
+            stats::frames_allocated(FrameUse::PageTable, 1);
+            self.entries[index].set(frame, flags | PRESENT);
+            self.next_table_direct_mut(index).unwrap().zero();
+        }
+        self.next_table_direct_mut(index)
+    }
+
+    /// Same as `free_next_table_if_empty`, but the table is accessed through the physical memory
+    /// direct map. The TLB isn't flushed.
+    pub fn free_next_table_direct_if_empty<A>(&mut self, index: usize, allocator: &mut A) -> bool
+        where A: FrameAllocator
+    {
+        match self.next_table_direct_mut(index) {
+            Some(table) => {
+                if !table.is_empty() {
+                    return false;
+                }
+            }
+            None => return false,
+        }
+
+        let frame = self.entries[index].pointed_frame().unwrap();
+        self.entries[index].set_unused();
+        allocator.deallocate_frame(frame);
+        stats::frames_freed(FrameUse::PageTable, 1);
+        true
+    }
+
+    /// Frees the next level table on the given index when all of its entries are unused. The
+    /// table frame is returned to the given `FrameAllocator`.
+    ///
+    /// ## Returns
+    /// `true` if the table was freed.
+    pub fn free_next_table_if_empty<A>(&mut self, index: usize, allocator: &mut A) -> bool
+        where A: FrameAllocator
+    {
+        use x86_64::VirtualAddress;
+        use x86_64::instructions::tlb;
+
+        let table_address = match self.next_table_address(index) {
+            Some(address) => address,
+            None => return false,
+        };
+
+        if !self.next_table(index).unwrap().is_empty() {
+            return false;
+        }
+
+        let frame = self.entries[index].pointed_frame().unwrap();
+        self.entries[index].set_unused();
+
+        // the table was accessible through the recursive mapping, remove it from the TLB
+        tlb::flush(VirtualAddress(table_address));
+
+        allocator.deallocate_frame(frame);
+        stats::frames_freed(FrameUse::PageTable, 1);
+        true
+    }
 }
 
 impl<L> Index<usize> for Table<L> where L: TableLevel
@@ -32,7 +32,8 @@ impl TemporaryPage {
 
     /// Unmaps the temporary page in the active table.
     pub fn unmap(&mut self, active_table: &mut ActivePageTable) {
-        active_table.unmap(self.page, &mut self.allocator)
+        // the mapped frame isn't owned by the temporary page, so it must not be freed
+        active_table.unmap_return(self.page, &mut self.allocator);
     }
 
     /// Maps the temporary page to the given page table frame in the active table.
@@ -0,0 +1,209 @@
+//! # Reserved Physical Memory
+//!
+//! Physical ranges that must never be handed out by the frame allocator: the kernel image, its
+//! symbol table, the multiboot information structure, the boot modules, the AP trampoline and the
+//! ACPI memory. The list lives on a fixed array since it is built before the heap is available.
+//!
+//! ACPI reclaimable memory only holds the ACPI tables, it is given back to the frame allocator by
+//! `memory::reclaim_acpi_memory` once they have been parsed.
+
+use core::slice;
+use multiboot2::{BootInformation, MemoryMapTag};
+use spin::Mutex;
+
+use backtrace;
+use memory::{PAGE_SIZE, kernel_virt_to_phys};
+use memory::paging::PhysicalAddress;
+
+/// Physical address where the application processors start executing, it matches the SIPI
+/// vector 0x08.
+pub const AP_TRAMPOLINE_ADDRESS: PhysicalAddress = 0x8000;
+
+/// Maximum number of reserved ranges.
+const MAX_RANGES: usize = 64;
+
+/// Memory map area types that aren't reported by `MemoryMapTag::memory_areas`.
+const AREA_ACPI_RECLAIMABLE: u32 = 3;
+const AREA_ACPI_NVS: u32 = 4;
+
+/// What a reserved range is used for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReservedKind {
+    Kernel,
+    /// Symbol table of the kernel and its string table, they resolve the backtraces
+    KernelSymbols,
+    Multiboot,
+    Module,
+    ApTrampoline,
+    /// ACPI tables, it can be reclaimed once the tables are parsed
+    AcpiReclaimable,
+    /// ACPI non-volatile storage, it must be preserved across sleep states
+    AcpiNvs,
+}
+
+/// A reserved range of physical memory.
+#[derive(Debug, Clone, Copy)]
+pub struct ReservedRange {
+    pub kind: ReservedKind,
+    pub start: PhysicalAddress,
+    /// End address (exclusive)
+    pub end: PhysicalAddress,
+}
+
+/// Fixed size list of reserved ranges.
+#[derive(Clone, Copy)]
+pub struct ReservedRanges {
+    ranges: [ReservedRange; MAX_RANGES],
+    len: usize,
+}
+
+impl ReservedRanges {
+    const fn new() -> ReservedRanges {
+        ReservedRanges {
+            ranges: [ReservedRange {
+                kind: ReservedKind::Kernel,
+                start: 0,
+                end: 0,
+            }; MAX_RANGES],
+            len: 0,
+        }
+    }
+
+    /// Add a range, empty ranges are ignored.
+    fn push(&mut self, kind: ReservedKind, start: PhysicalAddress, end: PhysicalAddress) {
+        if start >= end {
+            return;
+        }
+
+        assert!(self.len < MAX_RANGES, "too many reserved ranges");
+        self.ranges[self.len] = ReservedRange {
+            kind: kind,
+            start: start,
+            end: end,
+        };
+        self.len += 1;
+    }
+
+    /// Iterate the reserved ranges.
+    pub fn iter(&self) -> slice::Iter<ReservedRange> {
+        self.ranges[..self.len].iter()
+    }
+}
+
+static RESERVED: Mutex<ReservedRanges> = Mutex::new(ReservedRanges::new());
+
+/// Build the list of reserved ranges from the boot information, it is called once by
+/// `memory::init` before the frame allocator is created.
+///
+/// ## Params
+/// * `kernel_start` - physical address of the start of the kernel image.
+/// * `kernel_end` - physical address of the end of the kernel image (exclusive).
+pub fn init(boot_info: &BootInformation,
+            kernel_start: PhysicalAddress,
+            kernel_end: PhysicalAddress) {
+    let mut reserved = RESERVED.lock();
+
+    reserved.push(ReservedKind::Kernel, kernel_start, kernel_end);
+    if let Some(sections) = backtrace::find_sections(boot_info) {
+        reserved.push(ReservedKind::KernelSymbols,
+                      sections.symbols,
+                      sections.symbols + sections.symbols_size);
+        reserved.push(ReservedKind::KernelSymbols,
+                      sections.strings,
+                      sections.strings + sections.strings_size);
+    }
+    reserved.push(ReservedKind::Multiboot,
+                  kernel_virt_to_phys(boot_info.start_address()),
+                  kernel_virt_to_phys(boot_info.end_address()));
+
+    for module in boot_info.module_tags() {
+        reserved.push(ReservedKind::Module,
+                      module.start_address() as usize,
+                      module.end_address() as usize);
+    }
+
+    reserved.push(ReservedKind::ApTrampoline,
+                  AP_TRAMPOLINE_ADDRESS,
+                  AP_TRAMPOLINE_ADDRESS + PAGE_SIZE);
+
+    let memory_map_tag = boot_info.memory_map_tag().expect("Memory map tag required");
+    for (start, end, area_type) in all_memory_areas(memory_map_tag) {
+        match area_type {
+            AREA_ACPI_RECLAIMABLE => reserved.push(ReservedKind::AcpiReclaimable, start, end),
+            AREA_ACPI_NVS => reserved.push(ReservedKind::AcpiNvs, start, end),
+            _ => {}
+        }
+    }
+
+    for range in reserved.iter() {
+        debugln!("reserved {:?}: {:#x}-{:#x}", range.kind, range.start, range.end);
+    }
+}
+
+/// Get a copy of the reserved ranges.
+pub fn ranges() -> ReservedRanges {
+    *RESERVED.lock()
+}
+
+/// Remove all the ranges of the given kind from the list.
+///
+/// ## Returns
+/// The removed ranges.
+pub fn remove(kind: ReservedKind) -> ReservedRanges {
+    let mut reserved = RESERVED.lock();
+    let mut kept = ReservedRanges::new();
+    let mut removed = ReservedRanges::new();
+
+    for range in reserved.iter() {
+        if range.kind == kind {
+            removed.push(range.kind, range.start, range.end);
+        } else {
+            kept.push(range.kind, range.start, range.end);
+        }
+    }
+
+    *reserved = kept;
+    removed
+}
+
+/// Iterate every area of the memory map as `(start, end, type)`, `MemoryMapTag::memory_areas` only
+/// reports the available ones.
+fn all_memory_areas(tag: &MemoryMapTag) -> MemoryAreas {
+    // the tag starts with its type, size, entry size and entry version
+    let address = tag as *const _ as usize;
+    let size = unsafe { *((address + 4) as *const u32) } as usize;
+    let entry_size = unsafe { *((address + 8) as *const u32) } as usize;
+
+    MemoryAreas {
+        current: address + 16,
+        end: address + size,
+        entry_size: entry_size,
+    }
+}
+
+/// Iterator over the raw entries of the memory map tag.
+struct MemoryAreas {
+    current: usize,
+    end: usize,
+    entry_size: usize,
+}
+
+impl Iterator for MemoryAreas {
+    type Item = (PhysicalAddress, PhysicalAddress, u32);
+
+    fn next(&mut self) -> Option<(PhysicalAddress, PhysicalAddress, u32)> {
+        if self.current + self.entry_size > self.end {
+            return None;
+        }
+
+        // each entry holds the base address, the length and the type of the area
+        let (base, length, area_type) = unsafe {
+            (*(self.current as *const u64),
+             *((self.current + 8) as *const u64),
+             *((self.current + 16) as *const u32))
+        };
+        self.current += self.entry_size;
+
+        Some((base as usize, (base + length) as usize, area_type))
+    }
+}
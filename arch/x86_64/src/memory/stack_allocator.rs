@@ -1,24 +1,89 @@
-use memory::paging::{self, Page, PageIter, ActivePageTable};
+use collections::vec::Vec;
+use spin::Mutex;
+
+use memory::paging::{self, Page, PageIter, ActivePageTable, VirtualAddress};
 use memory::{PAGE_SIZE, FrameAllocator};
+use memory::stats::{self, FrameUse};
+
+/// Bounds of a stack in use, used to identify stack overflows.
+#[derive(Debug, Clone, Copy)]
+pub struct StackInfo {
+    /// Name given to the stack when it was allocated
+    pub name: &'static str,
+    pub top: usize,
+    pub bottom: usize,
+}
+
+lazy_static! {
+    /// All the stacks in use that have a guard page.
+    static ref STACKS: Mutex<Vec<StackInfo>> = Mutex::new(Vec::new());
+}
+
+/// Register a stack with an unmapped guard page right below `bottom`.
+pub fn register_stack(name: &'static str, top: usize, bottom: usize) {
+    STACKS.lock().push(StackInfo {
+        name: name,
+        top: top,
+        bottom: bottom,
+    });
+}
+
+/// Remove a stack from the registered stacks.
+fn unregister_stack(bottom: usize) {
+    STACKS.lock().retain(|stack| stack.bottom != bottom);
+}
+
+/// Find the stack whose guard page contains the given address.
+///
+/// This is used by the exception handlers, so it gives up instead of waiting for the lock.
+pub fn guard_page_owner(address: VirtualAddress) -> Option<StackInfo> {
+    STACKS.try_lock().and_then(|stacks| {
+        stacks.iter()
+            .find(|stack| address < stack.bottom && address >= stack.bottom - PAGE_SIZE)
+            .cloned()
+    })
+}
 
 pub struct StackAllocator {
     range: PageIter,
+    /// Stacks that were freed, their pages are unmapped and can be reused by a stack of the same
+    /// size. The guard page below each of them is kept.
+    free_stacks: Vec<Stack>,
 }
 
 impl StackAllocator {
     pub fn new(page_range: PageIter) -> StackAllocator {
-        StackAllocator { range: page_range }
+        StackAllocator {
+            range: page_range,
+            free_stacks: Vec::new(),
+        }
     }
 
     pub fn alloc_stack<FA: FrameAllocator>(&mut self,
                                            active_table: &mut ActivePageTable,
                                            frame_allocator: &mut FA,
-                                           size_in_pages: usize) -> Option<Stack> {
+                                           size_in_pages: usize,
+                                           name: &'static str) -> Option<Stack> {
         // a zero sized stack makes no sense
         if size_in_pages == 0 {
             return None;
         }
 
+        // reuse a freed stack with the same size
+        if let Some(index) = self.free_stacks
+            .iter()
+            .position(|stack| stack.size_in_pages() == size_in_pages) {
+            let stack = self.free_stacks.swap_remove(index);
+
+            for page in stack.pages() {
+                active_table.map(page, paging::WRITABLE, frame_allocator);
+            }
+
+            stats::frames_allocated(FrameUse::Stack, size_in_pages);
+            register_stack(name, stack.top, stack.bottom);
+            return Some(stack);
+        }
+
         // close the range, since we only want to change it on success
         let mut range = self.range.clone();
 
@@ -45,12 +110,30 @@ impl StackAllocator {
 
                 // create a new stack
                 let top_of_stack = end.start_address() + PAGE_SIZE;
+                stats::frames_allocated(FrameUse::Stack, size_in_pages);
+                register_stack(name, top_of_stack, start.start_address());
                 Some(Stack::new(top_of_stack, start.start_address()))
             }
             // not enough page
             _ => None,
         }
     }
+
+    /// Free a stack. Its frames are returned to the frame allocator and its pages are kept to be
+    /// reused by the next stack with the same size.
+    pub fn free_stack<FA: FrameAllocator>(&mut self,
+                                          active_table: &mut ActivePageTable,
+                                          frame_allocator: &mut FA,
+                                          stack: Stack) {
+        unregister_stack(stack.bottom);
+
+        for page in stack.pages() {
+            active_table.unmap(page, frame_allocator);
+        }
+
+        stats::frames_freed(FrameUse::Stack, stack.size_in_pages());
+        self.free_stacks.push(stack);
+    }
 }
 
 #[derive(Debug)]
@@ -75,4 +158,15 @@ impl Stack {
     pub fn bottom(&self) -> usize {
         self.bottom
     }
+
+    /// Number of pages used by the stack, without the guard page.
+    pub fn size_in_pages(&self) -> usize {
+        (self.top - self.bottom) / PAGE_SIZE
+    }
+
+    /// Iterate the pages of the stack.
+    fn pages(&self) -> PageIter {
+        Page::range_inclusive(Page::containing_address(self.bottom),
+                              Page::containing_address(self.top - 1))
+    }
 }
@@ -0,0 +1,158 @@
+//! # Memory Statistics
+//!
+//! Keeps track of what the allocated frames are used for. The frames of the heap are taken from
+//! the heap allocator and the frames that aren't part of any tracked use, like the boot page tables
+//! or the multiboot information structure, are reported as other.
+
+use core::fmt;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+use hole_list_allocator::{self, HeapStats};
+use memory::{PAGE_SIZE, FRAME_ALLOCATOR};
+
+/// Use of the frames tracked by `frames_allocated` and `frames_freed`.
+#[derive(Debug, Clone, Copy)]
+pub enum FrameUse {
+    PageTable,
+    Stack,
+    User,
+    /// Frames zeroed ahead of time, waiting on the zeroed frame pool
+    Zeroed,
+}
+
+/// Number of frames in use for each `FrameUse`.
+static FRAMES: [AtomicUsize; 4] = [AtomicUsize::new(0),
+                                   AtomicUsize::new(0),
+                                   AtomicUsize::new(0),
+                                   AtomicUsize::new(0)];
+
+/// Physical memory covered by the usable memory areas, including the holes between them.
+static TOTAL_MEMORY: AtomicUsize = AtomicUsize::new(0);
+
+/// Amount of usable physical memory.
+static USABLE_MEMORY: AtomicUsize = AtomicUsize::new(0);
+
+/// Number of frames used by the kernel image.
+static KERNEL_FRAMES: AtomicUsize = AtomicUsize::new(0);
+
+/// Record the physical memory layout, it is called once by `memory::init`.
+pub fn init(total_memory: usize, usable_memory: usize, kernel_frames: usize) {
+    TOTAL_MEMORY.store(total_memory, Ordering::Relaxed);
+    USABLE_MEMORY.store(usable_memory, Ordering::Relaxed);
+    KERNEL_FRAMES.store(kernel_frames, Ordering::Relaxed);
+}
+
+/// Record that `size` bytes of reserved memory were made usable.
+pub fn memory_reclaimed(size: usize) {
+    USABLE_MEMORY.fetch_add(size, Ordering::Relaxed);
+}
+
+/// Record that `count` frames were allocated for the given use.
+pub fn frames_allocated(usage: FrameUse, count: usize) {
+    FRAMES[usage as usize].fetch_add(count, Ordering::Relaxed);
+}
+
+/// Record that `count` frames used for the given use were freed.
+pub fn frames_freed(usage: FrameUse, count: usize) {
+    FRAMES[usage as usize].fetch_sub(count, Ordering::Relaxed);
+}
+
+/// Snapshot of the memory usage.
+///
+/// The layout is kept stable so it can be copied as is to user space.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct MemoryStats {
+    /// Physical memory covered by the usable memory areas, including the holes between them
+    pub total_memory: usize,
+    /// Physical memory that can be used by the kernel
+    pub usable_memory: usize,
+    /// Number of frames in use
+    pub used_frames: usize,
+    /// Number of frames that can still be allocated
+    pub free_frames: usize,
+    /// Frames used by the kernel image
+    pub kernel_frames: usize,
+    /// Frames used by page tables
+    pub page_table_frames: usize,
+    /// Frames mapped to the kernel heap
+    pub heap_frames: usize,
+    /// Frames mapped to kernel stacks
+    pub stack_frames: usize,
+    /// Frames mapped to user address spaces
+    pub user_frames: usize,
+    /// Zeroed frames waiting to be mapped to user address spaces
+    pub zeroed_frames: usize,
+    /// Frames in use that aren't part of any of the other uses
+    pub other_frames: usize,
+    /// Usage of the kernel heap
+    pub heap: HeapStats,
+}
+
+/// Get the current memory usage.
+pub fn stats() -> MemoryStats {
+    let (usable_frames, free_frames) = {
+        let frame_allocator = FRAME_ALLOCATOR.lock();
+        let frame_allocator = frame_allocator.as_ref().expect("frame allocator not initialized");
+        (frame_allocator.usable_frames(), frame_allocator.free_frames())
+    };
+
+    let heap = hole_list_allocator::stats();
+
+    let used_frames = usable_frames - free_frames;
+    let kernel_frames = KERNEL_FRAMES.load(Ordering::Relaxed);
+    let page_table_frames = FRAMES[FrameUse::PageTable as usize].load(Ordering::Relaxed);
+    let heap_frames = heap.size / PAGE_SIZE;
+    let stack_frames = FRAMES[FrameUse::Stack as usize].load(Ordering::Relaxed);
+    let user_frames = FRAMES[FrameUse::User as usize].load(Ordering::Relaxed);
+    let zeroed_frames = FRAMES[FrameUse::Zeroed as usize].load(Ordering::Relaxed);
+    let tracked_frames = kernel_frames + page_table_frames + heap_frames + stack_frames +
+                         user_frames + zeroed_frames;
+
+    MemoryStats {
+        total_memory: TOTAL_MEMORY.load(Ordering::Relaxed),
+        usable_memory: USABLE_MEMORY.load(Ordering::Relaxed),
+        used_frames: used_frames,
+        free_frames: free_frames,
+        kernel_frames: kernel_frames,
+        page_table_frames: page_table_frames,
+        heap_frames: heap_frames,
+        stack_frames: stack_frames,
+        user_frames: user_frames,
+        zeroed_frames: zeroed_frames,
+        other_frames: used_frames.saturating_sub(tracked_frames),
+        heap: heap,
+    }
+}
+
+impl fmt::Display for MemoryStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f,
+                 "memory: {} KiB usable of {} KiB, {} frames used, {} frames free",
+                 self.usable_memory / 1024,
+                 self.total_memory / 1024,
+                 self.used_frames,
+                 self.free_frames)?;
+        writeln!(f,
+                 "frames: kernel {}, page tables {}, heap {}, stacks {}, user {}, zeroed {}, \
+                  other {}",
+                 self.kernel_frames,
+                 self.page_table_frames,
+                 self.heap_frames,
+                 self.stack_frames,
+                 self.user_frames,
+                 self.zeroed_frames,
+                 self.other_frames)?;
+        write!(f,
+               "heap: {} KiB used of {} KiB (max {} KiB), slabs {} KiB with {} KiB free, \
+                largest hole {} KiB of {} KiB free, {}% fragmented",
+               self.heap.used() / 1024,
+               self.heap.size / 1024,
+               self.heap.max_size / 1024,
+               self.heap.slab_size / 1024,
+               self.heap.slab_free / 1024,
+               self.heap.largest_free / 1024,
+               self.heap.free / 1024,
+               self.heap.fragmentation())
+    }
+}
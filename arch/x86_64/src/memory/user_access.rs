@@ -0,0 +1,65 @@
+//! # User Memory Access
+//!
+//! With SMAP enabled the kernel faults when it touches user memory, unless the access is done
+//! between a `stac` and a `clac`. The only way for the kernel to read or write user memory is
+//! through the copy routines of this module, which check the range against the regions of the
+//! active address space before opening the access window.
+
+use core::ptr;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use memory::paging::{VirtualAddress, RegionError, check_user_range};
+
+/// Set when SMAP is enabled, `stac` and `clac` are invalid instructions without SMAP support. The
+/// interrupt entry of `interrupt_stubs.asm` reads it too.
+#[no_mangle]
+pub static SMAP_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Record whether SMAP was enabled by the boot code.
+pub fn init(smap_enabled: bool) {
+    SMAP_ENABLED.store(smap_enabled, Ordering::SeqCst);
+}
+
+/// Allow the kernel to access user pages.
+#[inline(always)]
+unsafe fn stac() {
+    if SMAP_ENABLED.load(Ordering::Relaxed) {
+        asm!("stac" ::: "memory" : "volatile");
+    }
+}
+
+/// Forbid the kernel to access user pages.
+#[inline(always)]
+unsafe fn clac() {
+    if SMAP_ENABLED.load(Ordering::Relaxed) {
+        asm!("clac" ::: "memory" : "volatile");
+    }
+}
+
+/// Copy `destination.len()` bytes from the user address `source` into `destination`.
+pub fn copy_from_user(destination: &mut [u8], source: VirtualAddress) -> Result<(), RegionError> {
+    check_user_range(source, destination.len(), false)?;
+
+    unsafe {
+        stac();
+        ptr::copy_nonoverlapping(source as *const u8,
+                                 destination.as_mut_ptr(),
+                                 destination.len());
+        clac();
+    }
+
+    Ok(())
+}
+
+/// Copy `source` to the user address `destination`.
+pub fn copy_to_user(destination: VirtualAddress, source: &[u8]) -> Result<(), RegionError> {
+    check_user_range(destination, source.len(), true)?;
+
+    unsafe {
+        stac();
+        ptr::copy_nonoverlapping(source.as_ptr(), destination as *mut u8, source.len());
+        clac();
+    }
+
+    Ok(())
+}
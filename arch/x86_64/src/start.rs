@@ -3,6 +3,7 @@ use memory;
 use multiboot2;
 use interrupts;
 use device;
+use backtrace;
 
 /// Enable the NXE bit to allow NO_EXECUTE pages.
 fn enable_nxe_bit() {
@@ -22,6 +23,58 @@ fn enable_write_protect_bit() {
     unsafe { cr0_write(cr0() | Cr0::WRITE_PROTECT) };
 }
 
+/// CR4 bit that forbids the `sgdt`, `sidt`, `sldt`, `smsw` and `str` instructions in user mode.
+const CR4_UMIP: u64 = 1 << 11;
+/// CR4 bit that forbids the kernel to execute code from user pages.
+const CR4_SMEP: u64 = 1 << 20;
+/// CR4 bit that forbids the kernel to access user pages outside of `stac`/`clac` blocks.
+const CR4_SMAP: u64 = 1 << 21;
+
+/// Check if the CPU supports UMIP, it isn't exposed by `raw_cpuid`.
+fn has_umip() -> bool {
+    let ecx: u32;
+    unsafe {
+        asm!("cpuid"
+             : "={ecx}"(ecx)
+             : "{eax}"(7), "{ecx}"(0)
+             : "eax", "ebx", "edx");
+    }
+    ecx & (1 << 2) != 0
+}
+
+/// Enable SMEP, SMAP and UMIP when they are supported by the CPU.
+fn enable_user_protection_bits() {
+    use raw_cpuid::CpuId;
+
+    let mut bits = 0;
+    let mut smap = false;
+
+    // all the features are reported on the extended features leaf
+    if let Some(info) = CpuId::new().get_extended_feature_info() {
+        if info.has_smep() {
+            bits |= CR4_SMEP;
+        }
+        if info.has_smap() {
+            bits |= CR4_SMAP;
+            smap = true;
+        }
+        if has_umip() {
+            bits |= CR4_UMIP;
+        }
+    }
+
+    unsafe {
+        let cr4: u64;
+        asm!("mov %cr4, $0" : "=r"(cr4));
+        asm!("mov $0, %cr4" :: "r"(cr4 | bits) : "memory" : "volatile");
+    }
+
+    memory::user_access::init(smap);
+
+    println!("SMEP: {}, SMAP: {}, UMIP: {}",
+             bits & CR4_SMEP != 0, smap, bits & CR4_UMIP != 0);
+}
+
 extern {
     /// Kernel main function
     fn kmain() -> !;
@@ -38,7 +91,11 @@ pub extern "C" fn rust_main(multiboot_information_address: usize) -> ! {
         // print out a welcome message
         println!("kernel: botting");
 
-        let boot_info = unsafe { multiboot2::load(multiboot_information_address) };
+        // the multiboot information structure is reached through the higher half mapping of the
+        // first GiB created by the boot code
+        let boot_info = unsafe {
+            multiboot2::load(multiboot_information_address + memory::KERNEL_OFFSET)
+        };
 
         // enable NXE bit, to allow define none executable pages.
         enable_nxe_bit();
@@ -46,16 +103,27 @@ pub extern "C" fn rust_main(multiboot_information_address: usize) -> ! {
         // set write protect bit in order to enable write protection on kernel mode.
         enable_write_protect_bit();
 
+        // keep the kernel from executing or touching user memory by accident
+        enable_user_protection_bits();
+
         // set up guard page and map the heap pages
         let mut memory_controller = memory::init(boot_info);
+        println!("{}", memory::stats());
+
+        // the kernel symbols are read through the direct map, they resolve the backtraces
+        backtrace::init(boot_info);
 
         // Initialize IDT
         interrupts::init(&mut memory_controller);
 
         // Initialize devices
-        device::init(&mut memory_controller);
+        device::init();
+
+        // TODO starts APs, each of them must call `memory::init_ap` before using the MMIO
+        // mappings
 
-        // TODO Read ACPI tables, starts APs
+        // the drivers are done with the ACPI tables, their memory can be reused
+        device::acpi::release_tables();
 
         // Initialize all the non-core devices
         device::init_non_core();
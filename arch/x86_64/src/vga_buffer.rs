@@ -6,6 +6,7 @@ use spin::Mutex;
 use volatile::Volatile;
 
 use device::serial::COM1;
+use memory::KERNEL_OFFSET;
 
 /// Print with new line to console
 #[macro_export]
@@ -14,6 +15,17 @@ macro_rules! println {
     ($fmt:expr, $($arg:tt)*) => (print!(concat!($fmt, "\n"), $($arg)*));
 }
 
+/// Print to console with a new line, only on debug builds. It is used for information that must not
+/// be shown on release builds, like kernel addresses.
+#[macro_export]
+macro_rules! debugln {
+    ($($arg:tt)*) => ({
+        if cfg!(debug_assertions) {
+            println!($($arg)*);
+        }
+    });
+}
+
 /// Print to console
 #[macro_export]
 macro_rules! print {
@@ -68,7 +80,7 @@ pub static WRITER: Mutex<Writer> = Mutex::new(Writer {
     row_positon: 0,
     column_position: 0,
     color_code: ColorCode::new(Color::Cyan, Color::White),
-    buffer: unsafe { Unique::new(0xb8000 as *mut _) },
+    buffer: unsafe { Unique::new((0xb8000 + KERNEL_OFFSET) as *mut _) },
 });
 
 /// Implement the Writer Struct
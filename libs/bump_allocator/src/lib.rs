@@ -8,11 +8,16 @@ use spin::Mutex;
 
 extern crate spin;
 
-pub const HEAP_START: usize = 0o_000_001_000_000_0000;
 pub const HEAP_SIZE: usize = 100 * 1024; // 100 KiB
 
 static BUMP_ALLOCATOR: Mutex<BumpAllocator> = Mutex::new(
-    BumpAllocator::new(HEAP_START, HEAP_SIZE));
+    BumpAllocator::new(0, HEAP_SIZE));
+
+/// Set the start address of the heap, it is chosen at boot by the kernel. It must be called before
+/// the first allocation.
+pub fn set_heap_start(start: usize) {
+    *BUMP_ALLOCATOR.lock() = BumpAllocator::new(start, HEAP_SIZE);
+}
 
 #[derive(Debug)]
 struct BumpAllocator {
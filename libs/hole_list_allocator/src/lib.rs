@@ -5,38 +5,257 @@
 
 #![feature(const_fn)]
 
+use core::{cmp, mem};
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use spin::Mutex;
 use linked_list_allocator::Heap;
 
 extern crate spin;
 extern crate linked_list_allocator;
 
-pub const HEAP_START: usize = 0o_000_001_000_000_0000;
+mod slab;
+
 pub const HEAP_SIZE: usize = 100 * 1024; // 100 KiB
 
+/// Minimum amount of memory added to the heap each time it grows.
+const GROW_STEP: usize = 64 * 1024; // 64 KiB
+
+/// Size of a page, the heap always grows by a multiple of it.
+const PAGE_SIZE: usize = 4096;
+
+/// Function used to map `size` bytes of memory starting at `start` when the heap needs to grow.
+/// It returns the number of bytes it mapped from `start`, a multiple of the page size that is less
+/// than `size` when it runs out of memory. The mapped bytes are added to the heap even when they
+/// are fewer than requested, so they are never mapped again.
+pub type GrowHandler = fn(start: usize, size: usize) -> usize;
+
+/// Function used to grow the heap and the maximum size the heap can reach.
+static GROW_HANDLER: Mutex<Option<(GrowHandler, usize)>> = Mutex::new(None);
+
+/// Start address of the heap, it is chosen at boot by the kernel.
+static HEAP_START: AtomicUsize = AtomicUsize::new(0);
+
+/// Number of bytes allocated from the hole list, including the slabs.
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+
+/// Byte written over the freed blocks when poisoning is enabled.
+const POISON_BYTE: u8 = 0xde;
+
+/// Set when the freed blocks are filled with `POISON_BYTE`.
+static POISON: AtomicBool = AtomicBool::new(false);
+
+/// Snapshot of the heap usage.
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct HeapStats {
+    /// Current size of the heap
+    pub size: usize,
+    /// Size the heap can grow to
+    pub max_size: usize,
+    /// Bytes allocated from the hole list, including the memory of the slabs
+    pub allocated: usize,
+    /// Bytes taken by the slab caches
+    pub slab_size: usize,
+    /// Bytes of the slab caches that aren't used by any object
+    pub slab_free: usize,
+    /// Bytes on the holes of the hole list
+    pub free: usize,
+    /// Size of the largest hole of the hole list
+    pub largest_free: usize,
+}
+
+impl HeapStats {
+    /// Number of bytes in use by allocations.
+    pub fn used(&self) -> usize {
+        self.allocated - self.slab_free
+    }
+
+    /// Percentage of the free memory of the hole list that is outside of its largest hole, so it
+    /// can't be used by an allocation as big as the free memory.
+    pub fn fragmentation(&self) -> usize {
+        if self.free == 0 {
+            0
+        } else {
+            100 - self.largest_free * 100 / self.free
+        }
+    }
+}
+
+/// Layout of a hole of `linked_list_allocator` 0.2, the holes are stored on the free memory itself.
+#[repr(C)]
+struct RawHole {
+    size: usize,
+    next: *const RawHole,
+}
+
+/// Layout of `linked_list_allocator::Heap` 0.2, the heap doesn't give access to its holes.
+#[repr(C)]
+struct RawHeap {
+    bottom: usize,
+    size: usize,
+    /// Head of the hole list, it is a dummy hole of size zero
+    first: RawHole,
+}
+
+/// Walk the hole list of the heap, the heap lock must be held.
+///
+/// ## Returns
+/// The number of free bytes and the size of the largest hole.
+fn holes(heap: &Heap) -> (usize, usize) {
+    assert!(mem::size_of::<Heap>() == mem::size_of::<RawHeap>(),
+            "unexpected layout of the heap");
+
+    let heap = unsafe { &*(heap as *const Heap as *const RawHeap) };
+    let (mut free, mut largest) = (0, 0);
+
+    let mut hole = heap.first.next;
+    while !hole.is_null() {
+        let size = unsafe { (*hole).size };
+        free += size;
+        largest = cmp::max(largest, size);
+        hole = unsafe { (*hole).next };
+    }
+
+    (free, largest)
+}
+
 
 #[macro_use]
 extern crate lazy_static;
 
 lazy_static! {
-    static ref HEAP: Mutex<Heap> = Mutex::new(unsafe {
-        Heap::new(HEAP_START, HEAP_SIZE)
-    });
+    static ref HEAP: Mutex<Heap> = {
+        // a heap at address zero would hand out memory that isn't mapped
+        let start = heap_start();
+        assert!(start != 0, "heap used before set_heap_start was called");
+
+        Mutex::new(unsafe { Heap::new(start, HEAP_SIZE) })
+    };
+}
+
+/// Set the start address of the heap. It must be called before the first allocation, the first
+/// `HEAP_SIZE` bytes must already be mapped.
+pub fn set_heap_start(start: usize) {
+    HEAP_START.store(start, Ordering::SeqCst);
+}
+
+/// Start address of the heap.
+pub fn heap_start() -> usize {
+    HEAP_START.load(Ordering::SeqCst)
+}
+
+/// Allow the heap to grow up to `max_size` bytes. The `handler` is called to map the memory at the
+/// end of the heap before it is used.
+pub fn set_grow_handler(handler: GrowHandler, max_size: usize) {
+    *GROW_HANDLER.lock() = Some((handler, max_size));
+}
+
+/// Fill the freed blocks with a poison pattern, to catch use-after-free bugs.
+pub fn set_poison(enabled: bool) {
+    POISON.store(enabled, Ordering::SeqCst);
+}
+
+/// Get the current heap usage.
+pub fn stats() -> HeapStats {
+    let (size, (free, largest_free)) = {
+        let heap = HEAP.lock();
+        (heap.size(), holes(&heap))
+    };
+    let max_size = match *GROW_HANDLER.lock() {
+        Some((_, max_size)) => max_size,
+        None => size,
+    };
+    let (slab_size, slab_free) = slab::stats();
+
+    HeapStats {
+        size: size,
+        max_size: max_size,
+        allocated: ALLOCATED.load(Ordering::Relaxed),
+        slab_size: slab_size,
+        slab_free: slab_free,
+        free: free,
+        largest_free: largest_free,
+    }
+}
+
+/// Grow the heap so that an allocation of `size` bytes aligned to `align` fits at its end.
+///
+/// ## Returns
+/// `false` when there is no grow handler, the heap reached its maximum size or no memory could be
+/// mapped.
+fn grow(heap: &mut Heap, size: usize, align: usize) -> bool {
+    let (handler, max_size) = match *GROW_HANDLER.lock() {
+        Some(grow_handler) => grow_handler,
+        None => return false,
+    };
+
+    // round the increment up to a whole number of pages
+    let increment = cmp::max(size + align, GROW_STEP);
+    let increment = (increment + PAGE_SIZE - 1) / PAGE_SIZE * PAGE_SIZE;
+
+    if heap.size() + increment > max_size {
+        return false;
+    }
+
+    let mapped = handler(heap.top(), increment);
+    if mapped == 0 {
+        return false;
+    }
+
+    unsafe { heap.extend(mapped) };
+    true
+}
+
+/// Allocate memory from the hole list, growing the heap when there is no hole big enough.
+fn heap_allocate(size: usize, align: usize) -> Option<*mut u8> {
+    let mut heap = HEAP.lock();
+
+    loop {
+        if let Some(ptr) = heap.allocate_first_fit(size, align) {
+            ALLOCATED.fetch_add(size, Ordering::Relaxed);
+            return Some(ptr);
+        }
+
+        if !grow(&mut heap, size, align) {
+            return None;
+        }
+    }
 }
 
 #[no_mangle]
 pub extern fn __rust_allocate(size: usize, align: usize) -> *mut u8 {
-    HEAP.lock().allocate_first_fit(size, align).expect("out of memory")
+    let ptr = match slab::size_class(size, align) {
+        Some(class) => {
+            slab::allocate(class, || heap_allocate(slab::SLAB_SIZE, slab::SLAB_SIZE))
+        }
+        None => heap_allocate(size, align),
+    };
+
+    ptr.expect("out of memory")
 }
 
 #[no_mangle]
 pub extern fn __rust_deallocate(ptr: *mut u8, size: usize, align: usize) {
-    unsafe { HEAP.lock().deallocate(ptr, size, align) };
+    // the allocator writes its own bookkeeping over the start of the block afterwards
+    if POISON.load(Ordering::Relaxed) {
+        unsafe { core::ptr::write_bytes(ptr, POISON_BYTE, size) };
+    }
+
+    match slab::size_class(size, align) {
+        Some(class) => slab::deallocate(class, ptr),
+        None => {
+            unsafe { HEAP.lock().deallocate(ptr, size, align) };
+            ALLOCATED.fetch_sub(size, Ordering::Relaxed);
+        }
+    }
 }
 
 #[no_mangle]
-pub extern fn __rust_usable_size(size: usize, _align: usize) -> usize {
-    size
+pub extern fn __rust_usable_size(size: usize, align: usize) -> usize {
+    match slab::size_class(size, align) {
+        Some(class) => slab::object_size(class),
+        None => size,
+    }
 }
 
 #[no_mangle]
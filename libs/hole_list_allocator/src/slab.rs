@@ -0,0 +1,142 @@
+//! # Slab Allocator
+//!
+//! Small allocations are served from caches of equally sized objects, one for each size class, so
+//! they don't need a first-fit walk of the hole list. Every cache has its own lock, so allocations
+//! of different sizes don't contend with each other. The memory of the caches is taken from the
+//! hole list heap, one slab at a time.
+
+use core::mem;
+use spin::Mutex;
+
+/// Size of the memory block taken from the heap when a cache runs out of objects.
+pub const SLAB_SIZE: usize = 4096;
+
+/// Number of size classes.
+const CLASS_COUNT: usize = 8;
+
+/// Object size of each cache. Slabs are aligned to their size, so every object is aligned to the
+/// object size.
+const SIZE_CLASSES: [usize; CLASS_COUNT] = [16, 32, 64, 128, 256, 512, 1024, 2048];
+
+static CACHES: [Mutex<SlabCache>; CLASS_COUNT] = [
+    Mutex::new(SlabCache::new(16)),
+    Mutex::new(SlabCache::new(32)),
+    Mutex::new(SlabCache::new(64)),
+    Mutex::new(SlabCache::new(128)),
+    Mutex::new(SlabCache::new(256)),
+    Mutex::new(SlabCache::new(512)),
+    Mutex::new(SlabCache::new(1024)),
+    Mutex::new(SlabCache::new(2048)),
+];
+
+/// A free object, the pointer to the next free object is stored on the object itself.
+struct FreeObject {
+    next: *mut FreeObject,
+}
+
+/// Cache of objects with the same size.
+struct SlabCache {
+    object_size: usize,
+    free_list: *mut FreeObject,
+    /// Number of slabs taken from the heap
+    slab_count: usize,
+    /// Number of objects on the free list
+    free_count: usize,
+}
+
+// the objects of the cache are only accessed while holding the cache lock
+unsafe impl Send for SlabCache {}
+
+impl SlabCache {
+    const fn new(object_size: usize) -> SlabCache {
+        SlabCache {
+            object_size: object_size,
+            free_list: 0 as *mut FreeObject,
+            slab_count: 0,
+            free_count: 0,
+        }
+    }
+
+    /// Split a new slab into objects and add them to the free list.
+    fn add_slab(&mut self, slab: *mut u8) {
+        self.slab_count += 1;
+        for index in 0..SLAB_SIZE / self.object_size {
+            let object = (slab as usize + index * self.object_size) as *mut u8;
+            self.push(object);
+        }
+    }
+
+    /// Take an object from the free list.
+    fn pop(&mut self) -> Option<*mut u8> {
+        if self.free_list.is_null() {
+            return None;
+        }
+
+        let object = self.free_list;
+        self.free_list = unsafe { (*object).next };
+        self.free_count -= 1;
+        Some(object as *mut u8)
+    }
+
+    /// Return an object to the free list.
+    fn push(&mut self, object: *mut u8) {
+        let object = object as *mut FreeObject;
+        unsafe { (*object).next = self.free_list };
+        self.free_list = object;
+        self.free_count += 1;
+    }
+}
+
+/// Find the size class for an allocation.
+///
+/// ## Returns
+/// The index of the size class, or `None` if the allocation is too large for the slab caches.
+pub fn size_class(size: usize, align: usize) -> Option<usize> {
+    // every object must be able to hold the free list pointer
+    let size = if size < mem::size_of::<FreeObject>() {
+        mem::size_of::<FreeObject>()
+    } else {
+        size
+    };
+
+    SIZE_CLASSES.iter().position(|&object_size| object_size >= size && object_size >= align)
+}
+
+/// Size of the objects of the given size class.
+pub fn object_size(class: usize) -> usize {
+    SIZE_CLASSES[class]
+}
+
+/// Allocate an object from the cache of the given size class. New slabs are taken from the heap
+/// with `allocate_slab` when the cache is empty.
+pub fn allocate<F>(class: usize, allocate_slab: F) -> Option<*mut u8>
+    where F: FnOnce() -> Option<*mut u8>
+{
+    let mut cache = CACHES[class].lock();
+
+    if cache.free_list.is_null() {
+        match allocate_slab() {
+            Some(slab) => cache.add_slab(slab),
+            None => return None,
+        }
+    }
+
+    cache.pop()
+}
+
+/// Return an object to the cache of the given size class.
+pub fn deallocate(class: usize, ptr: *mut u8) {
+    CACHES[class].lock().push(ptr);
+}
+
+/// Memory used by the slab caches.
+///
+/// ## Returns
+/// The number of bytes taken from the heap by all the caches and how many of them aren't used by
+/// any object.
+pub fn stats() -> (usize, usize) {
+    CACHES.iter().fold((0, 0), |(size, free), cache| {
+        let cache = cache.lock();
+        (size + cache.slab_count * SLAB_SIZE, free + cache.free_count * cache.object_size)
+    })
+}
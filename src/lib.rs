@@ -14,5 +14,10 @@ extern crate arch_x86_64 as arch;
 pub extern fn kmain() -> ! {
     println!("It did not crash!");
 
-    loop { }
+    loop {
+        // use the idle time to prepare zeroed frames for user space, `refill` returns once the pool
+        // is full or the frames ran out, so there is nothing left to do until the next interrupt
+        arch::memory::frame_pool::refill();
+        arch::interrupts::halt();
+    }
 }